@@ -5,6 +5,17 @@ mod m20221030_192706_add_last_update;
 mod m20221102_232244_add_join_table;
 mod m20221102_232858_remove_unused_columns;
 mod m20221106_211436_remove_query_x;
+mod m20221114_190432_add_source_transport;
+mod m20221115_101204_add_record_unchanged;
+mod m20221115_143517_add_rate_limit;
+mod m20221116_212950_add_source_kind;
+mod m20221117_094122_add_source_oauth;
+mod m20221118_170341_create_payloads_archive;
+mod m20221119_110825_add_source_headers;
+mod m20221120_152209_add_validation_bounds;
+mod m20221121_093047_add_metric_deadband;
+mod m20221122_181530_add_metric_retention;
+mod m20221123_204412_create_rollups;
 
 pub struct Migrator;
 
@@ -17,6 +28,17 @@ impl MigratorTrait for Migrator {
             Box::new(m20221102_232244_add_join_table::Migration),
             Box::new(m20221102_232858_remove_unused_columns::Migration),
             Box::new(m20221106_211436_remove_query_x::Migration),
+            Box::new(m20221114_190432_add_source_transport::Migration),
+            Box::new(m20221115_101204_add_record_unchanged::Migration),
+            Box::new(m20221115_143517_add_rate_limit::Migration),
+            Box::new(m20221116_212950_add_source_kind::Migration),
+            Box::new(m20221117_094122_add_source_oauth::Migration),
+            Box::new(m20221118_170341_create_payloads_archive::Migration),
+            Box::new(m20221119_110825_add_source_headers::Migration),
+            Box::new(m20221120_152209_add_validation_bounds::Migration),
+            Box::new(m20221121_093047_add_metric_deadband::Migration),
+            Box::new(m20221122_181530_add_metric_retention::Migration),
+            Box::new(m20221123_204412_create_rollups::Migration),
         ]
 	}
 }
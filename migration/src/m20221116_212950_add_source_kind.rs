@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(
+						ColumnDef::new(Sources::Kind)
+							.integer()
+							.not_null()
+							.default(0)
+					)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(
+						ColumnDef::new(Sources::Body)
+							.text()
+							.not_null()
+							.default("")
+					)
+					.to_owned()
+			).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for col in [Sources::Kind, Sources::Body] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Sources::Table)
+						.drop_column(col)
+						.to_owned()
+				).await?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Iden)]
+enum Sources {
+	Table,
+	Kind,
+	Body,
+}
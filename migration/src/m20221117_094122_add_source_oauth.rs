@@ -0,0 +1,48 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// sqlite can only add one column per statement
+		for col in [Sources::TokenUrl, Sources::ClientId, Sources::ClientSecret, Sources::Scope] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Sources::Table)
+						.add_column(
+							ColumnDef::new(col)
+								.string()
+								.not_null()
+								.default("")
+						)
+						.to_owned()
+				).await?;
+		}
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for col in [Sources::TokenUrl, Sources::ClientId, Sources::ClientSecret, Sources::Scope] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Sources::Table)
+						.drop_column(col)
+						.to_owned()
+				).await?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Iden)]
+enum Sources {
+	Table,
+	TokenUrl,
+	ClientId,
+	ClientSecret,
+	Scope,
+}
@@ -0,0 +1,83 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Payloads::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(Payloads::Id)
+							.big_integer()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(Payloads::SourceId).big_integer().not_null())
+					.col(ColumnDef::new(Payloads::FetchedAt).double().not_null())
+					.col(ColumnDef::new(Payloads::Html).boolean().not_null().default(false))
+					.col(ColumnDef::new(Payloads::Data).binary().not_null())
+					.to_owned(),
+			).await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("payloads-source-fetched-at")
+					.table(Payloads::Table)
+					.col(Payloads::SourceId)
+					.col(Payloads::FetchedAt)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(ColumnDef::new(Sources::Archive).boolean().not_null().default(false))
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(ColumnDef::new(Sources::ArchiveDays).integer().not_null().default(0))
+					.to_owned()
+			).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for col in [Sources::Archive, Sources::ArchiveDays] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Sources::Table)
+						.drop_column(col)
+						.to_owned()
+				).await?;
+		}
+		manager
+			.drop_table(Table::drop().table(Payloads::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Payloads {
+	Table,
+	Id,
+	SourceId,
+	FetchedAt,
+	Html,
+	Data,
+}
+
+#[derive(Iden)]
+enum Sources {
+	Table,
+	Archive,
+	ArchiveDays,
+}
@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(
+						ColumnDef::new(Sources::Headers)
+							.text()
+							.not_null()
+							.default("")
+					)
+					.to_owned()
+			).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.drop_column(Sources::Headers)
+					.to_owned()
+			).await
+	}
+}
+
+#[derive(Iden)]
+enum Sources {
+	Table,
+	Headers,
+}
@@ -0,0 +1,108 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// sqlite can only add one column per statement
+		for col in [Metrics::ValidMin, Metrics::ValidMax] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Metrics::Table)
+						.add_column(
+							ColumnDef::new(col)
+								.double()
+								.null()
+						)
+						.to_owned()
+				).await?;
+		}
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Metrics::Table)
+					.add_column(
+						ColumnDef::new(Metrics::OutOfRange)
+							.integer()
+							.not_null()
+							.default(0)
+					)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Points::Table)
+					.add_column(
+						ColumnDef::new(Points::Flagged)
+							.boolean()
+							.not_null()
+							.default(false)
+					)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.add_column(
+						ColumnDef::new(Sources::Rejected)
+							.big_integer()
+							.not_null()
+							.default(0)
+					)
+					.to_owned()
+			).await?;
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for col in [Metrics::ValidMin, Metrics::ValidMax, Metrics::OutOfRange] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Metrics::Table)
+						.drop_column(col)
+						.to_owned()
+				).await?;
+		}
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Points::Table)
+					.drop_column(Points::Flagged)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sources::Table)
+					.drop_column(Sources::Rejected)
+					.to_owned()
+			).await?;
+		Ok(())
+	}
+}
+
+#[derive(Iden)]
+enum Metrics {
+	Table,
+	ValidMin,
+	ValidMax,
+	OutOfRange,
+}
+
+#[derive(Iden)]
+enum Points {
+	Table,
+	Flagged,
+}
+
+#[derive(Iden)]
+enum Sources {
+	Table,
+	Rejected,
+}
@@ -0,0 +1,72 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// sqlite can only add one column per statement
+		for col in [Metrics::OnChange, Metrics::DeadbandPercent] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Metrics::Table)
+						.add_column(
+							ColumnDef::new(col)
+								.boolean()
+								.not_null()
+								.default(false)
+						)
+						.to_owned()
+				).await?;
+		}
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Metrics::Table)
+					.add_column(
+						ColumnDef::new(Metrics::Deadband)
+							.double()
+							.not_null()
+							.default(0.0)
+					)
+					.to_owned()
+			).await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Metrics::Table)
+					.add_column(
+						ColumnDef::new(Metrics::Heartbeat)
+							.integer()
+							.not_null()
+							.default(0)
+					)
+					.to_owned()
+			).await?;
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for col in [Metrics::OnChange, Metrics::Deadband, Metrics::DeadbandPercent, Metrics::Heartbeat] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Metrics::Table)
+						.drop_column(col)
+						.to_owned()
+				).await?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Iden)]
+enum Metrics {
+	Table,
+	OnChange,
+	Deadband,
+	DeadbandPercent,
+	Heartbeat,
+}
@@ -0,0 +1,37 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Metrics::Table)
+					.add_column(
+						ColumnDef::new(Metrics::Retention)
+							.integer()
+							.null()
+					)
+					.to_owned()
+			).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Metrics::Table)
+					.drop_column(Metrics::Retention)
+					.to_owned()
+			).await
+	}
+}
+
+#[derive(Iden)]
+enum Metrics {
+	Table,
+	Retention,
+}
@@ -0,0 +1,61 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Rollups::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(Rollups::Id)
+							.big_integer()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(Rollups::MetricId).big_integer().not_null())
+					.col(ColumnDef::new(Rollups::Resolution).integer().not_null())
+					.col(ColumnDef::new(Rollups::X).double().not_null())
+					.col(ColumnDef::new(Rollups::Min).double().not_null())
+					.col(ColumnDef::new(Rollups::Max).double().not_null())
+					.col(ColumnDef::new(Rollups::Avg).double().not_null())
+					.col(ColumnDef::new(Rollups::Count).big_integer().not_null())
+					.to_owned(),
+			).await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("rollups-metric-resolution-x")
+					.table(Rollups::Table)
+					.col(Rollups::MetricId)
+					.col(Rollups::Resolution)
+					.col(Rollups::X)
+					.unique()
+					.to_owned()
+			).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(Rollups::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Rollups {
+	Table,
+	Id,
+	MetricId,
+	Resolution,
+	X,
+	Min,
+	Max,
+	Avg,
+	Count,
+}
@@ -2,9 +2,31 @@
 
 use sea_orm::entity::prelude::*;
 
-use crate::data::FetchError;
+use crate::data::{FetchError, Payload};
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+/// What to do with values outside a metric valid range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+pub enum OutOfRange {
+	#[sea_orm(num_value = 0)]
+	Drop,
+	#[sea_orm(num_value = 1)]
+	Clamp,
+	#[sea_orm(num_value = 2)]
+	Flag,
+}
+
+impl OutOfRange {
+	pub fn label(&self) -> &'static str {
+		match self {
+			OutOfRange::Drop => "drop",
+			OutOfRange::Clamp => "clamp",
+			OutOfRange::Flag => "flag",
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "metrics")]
 pub struct Model {
 	#[sea_orm(primary_key, auto_increment = false)]
@@ -14,6 +36,14 @@ pub struct Model {
 	pub query: String,
 	pub color: i32,
 	pub position: i32,
+	pub valid_min: Option<f64>,
+	pub valid_max: Option<f64>,
+	pub out_of_range: OutOfRange,
+	pub on_change: bool,
+	pub deadband: f64,
+	pub deadband_percent: bool,
+	pub heartbeat: i32,
+	pub retention: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -50,8 +80,60 @@ impl Related<super::panels::Entity> for Entity {
 impl ActiveModelBehavior for ActiveModel {}
 
 impl Model {
-	pub fn extract(&self, value: &serde_json::Value) -> Result<Option<f64>, FetchError> {
-		Ok(jql::walker(value, self.query.as_str())?.as_f64())
+	/// Value found by query in payload. Null means no value this time, anything else which isn't
+	/// a number (or a string holding one) is an error.
+	pub fn extract(&self, payload: &Payload) -> Result<Option<f64>, FetchError> {
+		match payload {
+			Payload::Json(value) => match jql::walker(value, self.query.as_str())? {
+				serde_json::Value::Null => Ok(None),
+				serde_json::Value::Number(n) => Ok(n.as_f64()),
+				serde_json::Value::String(s) => Ok(Some(s.trim().parse::<f64>()?)),
+				v => Err(FetchError::JQLError(format!("not a number: {}", v))),
+			},
+			Payload::Html(doc) => doc.extract(&self.query),
+		}
+	}
+
+	/// Whether both bounds are set and min is above max. Such metrics can't be saved, and stored
+	/// ones are treated as having no bounds.
+	pub fn range_inverted(&self) -> bool {
+		matches!((self.valid_min, self.valid_max), (Some(min), Some(max)) if min > max)
+	}
+
+	/// Check an extracted value against valid range: returns value to store and whether it's
+	/// flagged, or None if it must be rejected. NaN and infinity are always rejected.
+	pub fn validate(&self, value: f64) -> Option<(f64, bool)> {
+		if !value.is_finite() {
+			return None;
+		}
+		if self.range_inverted() {
+			return Some((value, false));
+		}
+		let min = self.valid_min.unwrap_or(f64::NEG_INFINITY);
+		let max = self.valid_max.unwrap_or(f64::INFINITY);
+		if value >= min && value <= max {
+			return Some((value, false));
+		}
+		match self.out_of_range {
+			OutOfRange::Drop => None,
+			OutOfRange::Clamp => Some((value.clamp(min, max), false)),
+			OutOfRange::Flag => Some((value, true)),
+		}
+	}
+
+	/// Whether a value should be stored, given last stored point. Metrics recording on change
+	/// skip values within deadband (absolute, or percent of last value) unless `heartbeat`
+	/// minutes passed since last point.
+	pub fn should_store(&self, last: Option<(f64, f64)>, x: f64, y: f64) -> bool {
+		let Some((last_x, last_y)) = last else { return true };
+		if !self.on_change {
+			return true;
+		}
+		if self.heartbeat > 0 && x - last_x >= self.heartbeat as f64 * 60.0 {
+			return true;
+		}
+		let band = if self.deadband_percent { last_y.abs() * self.deadband / 100.0 } else { self.deadband };
+		if band > 0.0 { (y - last_y).abs() > band } else { y != last_y }
 	}
 }
 
@@ -64,7 +146,104 @@ impl Default for Model {
 			query: "".into(),
 			color: 0,
 			position: 0,
+			valid_min: None,
+			valid_max: None,
+			out_of_range: OutOfRange::Drop,
+			on_change: false,
+			deadband: 0.0,
+			deadband_percent: false,
+			heartbeat: 0,
+			retention: None,
 		}
 	}
 
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sea_orm::Iterable;
+
+	fn bounded(min: Option<f64>, max: Option<f64>, out_of_range: OutOfRange) -> Model {
+		Model { valid_min: min, valid_max: max, out_of_range, ..Default::default() }
+	}
+
+	#[test]
+	fn validate_applies_out_of_range_policy() {
+		let drop = bounded(Some(0.0), Some(10.0), OutOfRange::Drop);
+		assert_eq!(drop.validate(5.0), Some((5.0, false)));
+		assert_eq!(drop.validate(10.0), Some((10.0, false)));
+		assert_eq!(drop.validate(11.0), None);
+		let clamp = bounded(Some(0.0), Some(10.0), OutOfRange::Clamp);
+		assert_eq!(clamp.validate(-3.0), Some((0.0, false)));
+		assert_eq!(clamp.validate(11.0), Some((10.0, false)));
+		let flag = bounded(Some(0.0), None, OutOfRange::Flag);
+		assert_eq!(flag.validate(-3.0), Some((-3.0, true)));
+		assert_eq!(flag.validate(1e300), Some((1e300, false)));
+	}
+
+	#[test]
+	fn validate_rejects_non_finite() {
+		let m = Model::default();
+		assert_eq!(m.validate(f64::NAN), None);
+		assert_eq!(m.validate(f64::INFINITY), None);
+		assert_eq!(m.validate(1.5), Some((1.5, false)));
+	}
+
+	#[test]
+	fn validate_ignores_inverted_range() {
+		for policy in OutOfRange::iter() {
+			let m = bounded(Some(10.0), Some(0.0), policy);
+			assert!(m.range_inverted());
+			assert_eq!(m.validate(5.0), Some((5.0, false)));
+			assert_eq!(m.validate(-5.0), Some((-5.0, false)));
+		}
+		assert!(!bounded(Some(10.0), None, OutOfRange::Clamp).range_inverted());
+		assert!(!bounded(Some(1.0), Some(1.0), OutOfRange::Clamp).range_inverted());
+	}
+
+	fn on_change(deadband: f64, percent: bool, heartbeat: i32) -> Model {
+		Model { on_change: true, deadband, deadband_percent: percent, heartbeat, ..Default::default() }
+	}
+
+	#[test]
+	fn should_store_everything_unless_on_change() {
+		let m = Model::default();
+		assert!(m.should_store(None, 0.0, 1.0));
+		assert!(m.should_store(Some((0.0, 1.0)), 10.0, 1.0));
+		assert!(on_change(5.0, false, 0).should_store(None, 0.0, 1.0));
+	}
+
+	#[test]
+	fn should_store_skips_values_within_deadband() {
+		let exact = on_change(0.0, false, 0);
+		assert!(!exact.should_store(Some((0.0, 1.0)), 10.0, 1.0));
+		assert!(exact.should_store(Some((0.0, 1.0)), 10.0, 1.001));
+		let absolute = on_change(0.5, false, 0);
+		assert!(!absolute.should_store(Some((0.0, 10.0)), 10.0, 10.5));
+		assert!(!absolute.should_store(Some((0.0, 10.0)), 10.0, 9.6));
+		assert!(absolute.should_store(Some((0.0, 10.0)), 10.0, 10.6));
+		let percent = on_change(10.0, true, 0);
+		assert!(!percent.should_store(Some((0.0, -200.0)), 10.0, -181.0));
+		assert!(percent.should_store(Some((0.0, -200.0)), 10.0, -179.0));
+	}
+
+	#[test]
+	fn should_store_after_heartbeat() {
+		let m = on_change(100.0, false, 5);
+		assert!(!m.should_store(Some((0.0, 1.0)), 299.0, 2.0));
+		assert!(m.should_store(Some((0.0, 1.0)), 300.0, 1.0));
+	}
+
+	#[test]
+	fn extract_tells_missing_values_from_broken_queries() {
+		let payload = Payload::Json(serde_json::json!({ "n": 3, "s": " 4.5", "none": null, "text": "abc", "list": [1] }));
+		let query = |q: &str| Model { query: format!("\"{}\"", q), ..Default::default() }.extract(&payload);
+		assert_eq!(query("n").unwrap(), Some(3.0));
+		assert_eq!(query("s").unwrap(), Some(4.5));
+		assert_eq!(query("none").unwrap(), None);
+		assert!(query("text").is_err());
+		assert!(query("list").is_err());
+	}
+}
+
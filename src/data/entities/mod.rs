@@ -7,3 +7,5 @@ pub mod panel_metric;
 pub mod metrics;
 pub mod points;
 pub mod sources;
+pub mod payloads;
+pub mod rollups;
@@ -0,0 +1,62 @@
+use std::io::{Read, Write};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use sea_orm::{entity::prelude::*, Set, ActiveValue::NotSet};
+
+use crate::data::{FetchError, Payload, html::Document};
+
+/// Raw payload fetched from a source, kept compressed to backfill metrics added later
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "payloads")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i64,
+	pub source_id: i64,
+	pub fetched_at: f64,
+	pub html: bool,
+	pub data: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::sources::Entity",
+		from = "Column::SourceId",
+		to = "super::sources::Column::Id"
+	)]
+	Source,
+}
+
+impl Related<super::sources::Entity> for Entity {
+	fn to() -> RelationDef { Relation::Source.def() }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+	pub fn pack(source_id: i64, fetched_at: f64, payload: &Payload) -> Result<ActiveModel, FetchError> {
+		let (html, raw) = match payload {
+			Payload::Json(value) => (false, serde_json::to_vec(value).unwrap_or_default()),
+			Payload::Html(doc) => (true, doc.source.as_bytes().to_vec()),
+		};
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(&raw)?;
+		Ok(ActiveModel {
+			id: NotSet,
+			source_id: Set(source_id),
+			fetched_at: Set(fetched_at),
+			html: Set(html),
+			data: Set(encoder.finish()?),
+		})
+	}
+
+	pub fn unpack(&self) -> Result<Payload, FetchError> {
+		let mut raw = String::new();
+		GzDecoder::new(self.data.as_slice()).read_to_string(&mut raw)?;
+		if self.html {
+			Ok(Payload::Html(Document::parse(raw)))
+		} else {
+			Ok(Payload::Json(serde_json::from_str(&raw).map_err(std::io::Error::from)?))
+		}
+	}
+}
@@ -10,6 +10,7 @@ pub struct Model {
 	pub metric_id: i64,
 	pub x: f64,
 	pub y: f64,
+	pub flagged: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,46 @@
+use sea_orm::entity::prelude::*;
+
+/// Aggregate of a metric points falling in one bucket, `resolution` seconds wide starting at `x`
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "rollups")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i64,
+	pub metric_id: i64,
+	pub resolution: i32,
+	pub x: f64,
+	pub min: f64,
+	pub max: f64,
+	pub avg: f64,
+	pub count: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::metrics::Entity",
+		from = "Column::MetricId",
+		to = "super::metrics::Column::Id"
+	)]
+	Metric,
+}
+
+impl Related<super::metrics::Entity> for Entity {
+	fn to() -> RelationDef { Relation::Metric.def() }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Rollups are plotted like points, at bucket center with average value. Ids are negated so
+/// they can't be mistaken for rows of `points`.
+impl From<Model> for super::points::Model {
+	fn from(r: Model) -> Self {
+		super::points::Model {
+			id: -r.id,
+			metric_id: r.metric_id,
+			x: r.x + r.resolution as f64 / 2.0,
+			y: r.avg,
+			flagged: false,
+		}
+	}
+}
@@ -1,7 +1,32 @@
 use sea_orm::entity::prelude::*;
 use chrono::Utc;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+/// How a source is fetched: `url` is a web address, a database connection string or a target to check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+pub enum SourceKind {
+	#[sea_orm(num_value = 0)]
+	Http,
+	#[sea_orm(num_value = 1)]
+	Sql,
+	#[sea_orm(num_value = 2)]
+	Uptime,
+	#[sea_orm(num_value = 3)]
+	Html,
+}
+
+impl SourceKind {
+	pub fn label(&self) -> &'static str {
+		match self {
+			SourceKind::Http => "http",
+			SourceKind::Sql => "sql",
+			SourceKind::Uptime => "uptime",
+			SourceKind::Html => "html",
+		}
+	}
+}
+
+#[derive(Clone, PartialEq, DeriveEntityModel, Eq)]
 #[sea_orm(table_name = "sources")]
 pub struct Model {
 	#[sea_orm(primary_key, auto_increment = false)]
@@ -12,12 +37,75 @@ pub struct Model {
 	pub interval: i32,
 	pub last_update: i64,
 	pub position: i32,
+	pub ca_bundle: String,
+	pub client_cert: String,
+	pub client_key: String,
+	pub insecure: bool,
+	pub proxy: String,
+	pub record_unchanged: bool,
+	pub rate_limit: i32,
+	pub kind: SourceKind,
+	pub body: String,
+	pub token_url: String,
+	pub client_id: String,
+	/// `env:VAR`, `file:/path` or the secret itself, which is then stored in plain text
+	pub client_secret: String,
+	pub scope: String,
+	pub archive: bool,
+	pub archive_days: i32,
+	pub headers: String,
+	pub rejected: i64,
+}
+
+impl Model {
+	/// Client secret fit for logs: `env:` and `file:` references are shown, literal secrets aren't
+	pub fn client_secret_repr(&self) -> &str {
+		if self.client_secret.is_empty() || self.client_secret.starts_with("env:") || self.client_secret.starts_with("file:") {
+			&self.client_secret
+		} else {
+			"<redacted>"
+		}
+	}
+}
+
+// written by hand to keep literal client secrets out of logs
+impl std::fmt::Debug for Model {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Model")
+			.field("id", &self.id)
+			.field("name", &self.name)
+			.field("enabled", &self.enabled)
+			.field("url", &self.url)
+			.field("interval", &self.interval)
+			.field("last_update", &self.last_update)
+			.field("position", &self.position)
+			.field("ca_bundle", &self.ca_bundle)
+			.field("client_cert", &self.client_cert)
+			.field("client_key", &self.client_key)
+			.field("insecure", &self.insecure)
+			.field("proxy", &self.proxy)
+			.field("record_unchanged", &self.record_unchanged)
+			.field("rate_limit", &self.rate_limit)
+			.field("kind", &self.kind)
+			.field("body", &self.body)
+			.field("token_url", &self.token_url)
+			.field("client_id", &self.client_id)
+			.field("client_secret", &self.client_secret_repr())
+			.field("scope", &self.scope)
+			.field("archive", &self.archive)
+			.field("archive_days", &self.archive_days)
+			.field("headers", &self.headers)
+			.field("rejected", &self.rejected)
+			.finish()
+	}
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
 	#[sea_orm(has_many = "super::metrics::Entity")]
 	Metric,
+	#[sea_orm(has_many = "super::payloads::Entity")]
+	Payload,
 }
 
 impl Related<super::metrics::Entity> for Entity {
@@ -26,6 +114,12 @@ impl Related<super::metrics::Entity> for Entity {
 	}
 }
 
+impl Related<super::payloads::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Payload.def()
+	}
+}
+
 impl ActiveModelBehavior for ActiveModel {}
 
 impl Model {
@@ -50,6 +144,38 @@ impl Default for Model {
 			interval: 60,
 			last_update: 0,
 			position: 0,
+			ca_bundle: "".into(),
+			client_cert: "".into(),
+			client_key: "".into(),
+			insecure: false,
+			proxy: "".into(),
+			record_unchanged: true,
+			rate_limit: 0,
+			kind: SourceKind::Http,
+			body: "".into(),
+			token_url: "".into(),
+			client_id: "".into(),
+			client_secret: "".into(),
+			scope: "".into(),
+			archive: false,
+			archive_days: 0,
+			headers: "".into(),
+			rejected: 0,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn debug_redacts_literal_secrets() {
+		let mut source = Model { client_secret: "hunter2".into(), ..Default::default() };
+		let repr = format!("{:?}", source);
+		assert!(!repr.contains("hunter2"));
+		assert!(repr.contains("<redacted>"));
+		source.client_secret = "env:SECRET".into();
+		assert!(format!("{:?}", source).contains("env:SECRET"));
+	}
+}
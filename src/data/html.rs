@@ -0,0 +1,568 @@
+use regex::Regex;
+
+use super::FetchError;
+
+// elements which never have children nor a closing tag
+const VOID : &[&str] = &["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr"];
+// elements whose content is not markup
+const RAW_TEXT : &[&str] = &["script", "style", "textarea", "title"];
+// elements which implicitly close some open elements, when missing their end tag
+const AUTO_CLOSE : &[(&str, &[&str])] = &[
+	("p", &["p"]), ("li", &["li"]), ("dt", &["dt", "dd"]), ("dd", &["dt", "dd"]),
+	("tr", &["tr", "td", "th"]), ("td", &["td", "th"]), ("th", &["td", "th"]), ("option", &["option"]),
+];
+
+#[derive(Debug, Clone)]
+enum Child {
+	Element(usize),
+	Text(String),
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+	name: String,
+	attrs: Vec<(String, String)>,
+	parent: usize,
+	children: Vec<Child>,
+}
+
+/// Parsed html page. Parsing is lenient: unknown or unclosed tags never fail, like browsers do
+#[derive(Debug, Clone)]
+pub struct Document {
+	pub source: String,
+	elements: Vec<Element>, // 0 is document root
+}
+
+fn decode_entities(text: &str) -> String {
+	if !text.contains('&') {
+		return text.to_string();
+	}
+	let mut out = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find('&') {
+		out.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+			let entity = &rest[1..end];
+			let c = match entity {
+				"amp" => Some('&'),
+				"lt" => Some('<'),
+				"gt" => Some('>'),
+				"quot" => Some('"'),
+				"apos" => Some('\''),
+				"nbsp" => Some(' '),
+				_ if entity.starts_with("#x") || entity.starts_with("#X") =>
+					u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+				_ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+				_ => None,
+			};
+			c.map(|c| (c, end))
+		});
+		match decoded {
+			Some((c, end)) => {
+				out.push(c);
+				rest = &rest[end + 1..];
+			},
+			None => {
+				out.push('&');
+				rest = &rest[1..];
+			},
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+// position of '>' ending a tag, skipping quoted attribute values
+fn tag_end(s: &str) -> Option<usize> {
+	let mut quote = None;
+	for (i, c) in s.char_indices() {
+		match (quote, c) {
+			(None, '>') => return Some(i),
+			(None, '"' | '\'') => quote = Some(c),
+			(Some(q), c) if q == c => quote = None,
+			_ => {},
+		}
+	}
+	None
+}
+
+fn parse_attrs(mut s: &str) -> Vec<(String, String)> {
+	let mut attrs = vec![];
+	loop {
+		s = s.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+		if s.is_empty() {
+			break;
+		}
+		let name_end = s.find(|c: char| c.is_whitespace() || c == '=' || c == '/').unwrap_or(s.len());
+		let name = s[..name_end].to_lowercase();
+		s = s[name_end..].trim_start();
+		let mut value = String::new();
+		if let Some(after) = s.strip_prefix('=') {
+			let after = after.trim_start();
+			match after.chars().next() {
+				Some(q) if q == '"' || q == '\'' => {
+					let end = after[1..].find(q).map(|e| e + 1).unwrap_or(after.len());
+					value = decode_entities(&after[1..end]);
+					s = after.get(end + 1..).unwrap_or("");
+				},
+				_ => {
+					let end = after.find(char::is_whitespace).unwrap_or(after.len());
+					value = decode_entities(&after[..end]);
+					s = &after[end..];
+				},
+			}
+		}
+		if !name.is_empty() {
+			attrs.push((name, value));
+		}
+	}
+	attrs
+}
+
+impl Document {
+	pub fn parse(source: String) -> Self {
+		let mut elements = vec![Element { name: "#document".into(), attrs: vec![], parent: 0, children: vec![] }];
+		let mut stack = vec![0usize];
+		let mut rest = source.as_str();
+
+		while !rest.is_empty() {
+			let current = *stack.last().unwrap_or(&0);
+			let Some(lt) = rest.find('<') else {
+				elements[current].children.push(Child::Text(decode_entities(rest)));
+				break;
+			};
+			if lt > 0 {
+				elements[current].children.push(Child::Text(decode_entities(&rest[..lt])));
+				rest = &rest[lt..];
+			}
+			if let Some(comment) = rest.strip_prefix("<!--") {
+				rest = comment.find("-->").map(|e| &comment[e + 3..]).unwrap_or("");
+				continue;
+			}
+			let Some(gt) = tag_end(rest) else {
+				elements[current].children.push(Child::Text(decode_entities(rest)));
+				break;
+			};
+			let tag = &rest[1..gt];
+			rest = &rest[gt + 1..];
+
+			if tag.starts_with('!') || tag.starts_with('?') {
+				continue; // doctype or processing instruction
+			}
+			if let Some(closing) = tag.strip_prefix('/') {
+				let name = closing.trim().to_lowercase();
+				if let Some(pos) = stack.iter().rposition(|e| elements[*e].name == name) {
+					if pos > 0 {
+						stack.truncate(pos);
+					}
+				}
+				continue;
+			}
+
+			let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+			let name = tag[..name_end].to_lowercase();
+			if name.is_empty() || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+				elements[current].children.push(Child::Text(format!("<{}>", tag)));
+				continue;
+			}
+			if let Some((_, closes)) = AUTO_CLOSE.iter().find(|(n, _)| *n == name) {
+				while stack.len() > 1 && closes.contains(&elements[*stack.last().unwrap_or(&0)].name.as_str()) {
+					stack.pop();
+				}
+			}
+			let parent = *stack.last().unwrap_or(&0);
+			let id = elements.len();
+			elements.push(Element { name: name.clone(), attrs: parse_attrs(&tag[name_end..]), parent, children: vec![] });
+			elements[parent].children.push(Child::Element(id));
+
+			if RAW_TEXT.contains(&name.as_str()) {
+				let end = rest.to_ascii_lowercase().find(&format!("</{}", name)).unwrap_or(rest.len());
+				elements[id].children.push(Child::Text(decode_entities(&rest[..end])));
+				rest = &rest[end..];
+				rest = rest.find('>').map(|e| &rest[e + 1..]).unwrap_or("");
+			} else if !VOID.contains(&name.as_str()) && !tag.ends_with('/') {
+				stack.push(id);
+			}
+		}
+
+		Document { source, elements }
+	}
+
+	fn attr(&self, el: usize, name: &str) -> Option<&str> {
+		self.elements[el].attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+	}
+
+	/// Text inside element and its descendants, with whitespace collapsed
+	fn text(&self, el: usize) -> String {
+		fn collect(doc: &Document, el: usize, out: &mut String) {
+			if matches!(doc.elements[el].name.as_str(), "script" | "style") {
+				return;
+			}
+			for child in doc.elements[el].children.iter() {
+				match child {
+					Child::Text(t) => { out.push_str(t); out.push(' '); },
+					Child::Element(e) => collect(doc, *e, out),
+				}
+			}
+		}
+		let mut out = String::new();
+		collect(self, el, &mut out);
+		out.split_whitespace().collect::<Vec<&str>>().join(" ")
+	}
+
+	fn siblings(&self, el: usize) -> Vec<usize> {
+		self.elements[self.elements[el].parent].children.iter()
+			.filter_map(|c| match c { Child::Element(e) => Some(*e), Child::Text(_) => None })
+			.collect()
+	}
+
+	fn matches_compound(&self, el: usize, sel: &Compound) -> bool {
+		let element = &self.elements[el];
+		if el == 0 { return false; }
+		if let Some(tag) = &sel.tag {
+			if *tag != element.name { return false; }
+		}
+		if let Some(id) = &sel.id {
+			if self.attr(el, "id") != Some(id.as_str()) { return false; }
+		}
+		let classes : Vec<&str> = self.attr(el, "class").unwrap_or("").split_whitespace().collect();
+		if !sel.classes.iter().all(|c| classes.contains(&c.as_str())) {
+			return false;
+		}
+		for (name, value) in sel.attrs.iter() {
+			match (self.attr(el, name), value) {
+				(None, _) => return false,
+				(Some(v), Some(expected)) if v != expected => return false,
+				_ => {},
+			}
+		}
+		if let Some(nth) = &sel.nth {
+			let siblings = self.siblings(el);
+			let pos = siblings.iter().position(|e| *e == el).unwrap_or(0);
+			let ok = match nth {
+				Nth::Index(n) => pos + 1 == *n,
+				Nth::Last => pos + 1 == siblings.len(),
+			};
+			if !ok { return false; }
+		}
+		true
+	}
+
+	// match selector parts right to left, backtracking over ancestors for descendant combinators
+	fn matches(&self, el: usize, parts: &[(Combinator, Compound)]) -> bool {
+		let Some(((combinator, compound), rest)) = parts.split_last() else { return true };
+		if !self.matches_compound(el, compound) {
+			return false;
+		}
+		if rest.is_empty() {
+			return true;
+		}
+		match combinator {
+			Combinator::Child => el != 0 && self.matches(self.elements[el].parent, rest),
+			Combinator::Descendant => {
+				let mut ancestor = self.elements[el].parent;
+				while ancestor != 0 {
+					if self.matches(ancestor, rest) {
+						return true;
+					}
+					ancestor = self.elements[ancestor].parent;
+				}
+				false
+			},
+		}
+	}
+
+	/// First element matching selector, in document order
+	fn select(&self, selector: &Selector) -> Option<usize> {
+		(1..self.elements.len()).find(|el| selector.0.iter().any(|parts| self.matches(*el, parts)))
+	}
+
+	/// Run a query like `selector [@attribute] [| regex]`: text (or attribute) of first matching
+	/// element is searched with regex, using its first group if any, or `default` if no regex is given
+	fn lookup(&self, query: &str, default: Option<&str>) -> Result<Option<String>, FetchError> {
+		// regex starts at first '|' outside selector brackets, and may contain more of them
+		let (selector, pattern) = match find_top_level(query, '|') {
+			Some(i) => (query[..i].trim(), Some(query[i + 1..].trim())),
+			None => (query.trim(), None),
+		};
+		// attribute follows last whitespace outside selector brackets, quoted values may contain '@'
+		let last_space = top_level(selector).filter(|(_, c)| c.is_whitespace()).last();
+		let (selector, attribute) = match last_space.map(|(i, c)| (&selector[..i], &selector[i + c.len_utf8()..])) {
+			Some((s, a)) if a.starts_with('@') => (s, Some(&a[1..])),
+			_ if selector.starts_with('@') => ("*", Some(&selector[1..])),
+			_ => (selector, None),
+		};
+		let selector = Selector::parse(selector)?;
+		let re = match pattern.or(default) {
+			Some(p) => Some(Regex::new(p).map_err(|e| FetchError::SelectorError(e.to_string()))?),
+			None => None,
+		};
+
+		let Some(el) = self.select(&selector) else { return Ok(None) };
+		let text = match attribute {
+			Some(a) => match self.attr(el, a) {
+				Some(v) => v.to_string(),
+				None => return Ok(None),
+			},
+			None => self.text(el),
+		};
+		let Some(re) = re else { return Ok(Some(text)) };
+		let Some(captures) = re.captures(&text) else { return Ok(None) };
+		Ok(captures.get(1).or_else(|| captures.get(0)).map(|m| m.as_str().to_string()))
+	}
+
+	/// Extract a number with a query like `selector [@attribute] [| regex]`. Without a regex the
+	/// first number found is used. Thousands separators (`,`) are ignored.
+	pub fn extract(&self, query: &str) -> Result<Option<f64>, FetchError> {
+		Ok(
+			self.lookup(query, Some(r"-?[0-9][0-9,]*(?:\.[0-9]+)?"))?
+				.and_then(|found| found.replace(',', "").trim().parse::<f64>().ok())
+		)
+	}
+
+	/// Extract text with a query like `selector [@attribute] [| regex]`
+	pub fn extract_text(&self, query: &str) -> Result<Option<String>, FetchError> {
+		self.lookup(query, None)
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Combinator {
+	Descendant,
+	Child,
+}
+
+#[derive(Debug)]
+enum Nth {
+	Index(usize),
+	Last,
+}
+
+#[derive(Debug, Default)]
+struct Compound {
+	tag: Option<String>,
+	id: Option<String>,
+	classes: Vec<String>,
+	attrs: Vec<(String, Option<String>)>,
+	nth: Option<Nth>,
+}
+
+/// Subset of css selectors: tags, `#id`, `.class`, `[attr]`, `[attr=value]`, `:first-child`,
+/// `:last-child`, `:nth-child(n)`, descendant and `>` combinators and `,` alternatives
+#[derive(Debug)]
+struct Selector(Vec<Vec<(Combinator, Compound)>>);
+
+// characters of `s` not inside brackets, parentheses or quotes, with their positions
+fn top_level(s: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+	let mut quote = None;
+	let mut depth = 0;
+	s.char_indices().filter(move |&(_, c)| {
+		match (quote, c) {
+			(Some(q), c) if q == c => quote = None,
+			(Some(_), _) => {},
+			(None, '"' | '\'') => quote = Some(c),
+			(None, '[' | '(') => depth += 1,
+			(None, ']' | ')') => depth -= 1,
+			(None, _) if depth <= 0 => return true,
+			_ => {},
+		}
+		false
+	})
+}
+
+// position of first `sep` not inside brackets, parentheses or quotes
+fn find_top_level(s: &str, sep: char) -> Option<usize> {
+	top_level(s).find(|&(_, c)| c == sep).map(|(i, _)| i)
+}
+
+fn ident(s: &str) -> (&str, &str) {
+	let end = s.find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_')).unwrap_or(s.len());
+	s.split_at(end)
+}
+
+impl Selector {
+	fn parse(selector: &str) -> Result<Self, FetchError> {
+		let err = |msg: &str| FetchError::SelectorError(format!("{} in selector '{}'", msg, selector));
+		let mut alternatives = vec![];
+		let mut remaining = selector;
+		loop {
+			let (alternative, next) = match find_top_level(remaining, ',') {
+				Some(i) => (&remaining[..i], Some(&remaining[i + 1..])),
+				None => (remaining, None),
+			};
+			let mut parts = vec![];
+			let mut combinator = Combinator::Descendant;
+			let mut rest = alternative.trim();
+			if rest.is_empty() {
+				return Err(err("empty selector"));
+			}
+			while !rest.is_empty() {
+				if let Some(r) = rest.strip_prefix('>') {
+					combinator = Combinator::Child;
+					rest = r.trim_start();
+					continue;
+				}
+				let mut compound = Compound::default();
+				loop {
+					if let Some(r) = rest.strip_prefix('*') {
+						rest = r;
+					} else if let Some(r) = rest.strip_prefix('#') {
+						let (id, r) = ident(r);
+						compound.id = Some(id.to_string());
+						rest = r;
+					} else if let Some(r) = rest.strip_prefix('.') {
+						let (class, r) = ident(r);
+						compound.classes.push(class.to_string());
+						rest = r;
+					} else if let Some(r) = rest.strip_prefix('[') {
+						let end = r.find(']').ok_or_else(|| err("unclosed '['"))?;
+						let attr = match r[..end].split_once('=') {
+							Some((k, v)) => (k.trim().to_lowercase(), Some(v.trim().trim_matches(|c| c == '"' || c == '\'').to_string())),
+							None => (r[..end].trim().to_lowercase(), None),
+						};
+						compound.attrs.push(attr);
+						rest = &r[end + 1..];
+					} else if let Some(r) = rest.strip_prefix(':') {
+						let (pseudo, r) = ident(r);
+						rest = r;
+						compound.nth = Some(match pseudo {
+							"first-child" => Nth::Index(1),
+							"last-child" => Nth::Last,
+							"nth-child" => {
+								let inner = rest.strip_prefix('(').and_then(|r| r.split_once(')')).ok_or_else(|| err("expected (n)"))?;
+								rest = inner.1;
+								Nth::Index(inner.0.trim().parse().map_err(|_| err("expected a number"))?)
+							},
+							_ => return Err(err(&format!("unsupported pseudo-class ':{}'", pseudo))),
+						});
+					} else if rest.starts_with(|c: char| c.is_alphabetic()) {
+						let (tag, r) = ident(rest);
+						compound.tag = Some(tag.to_lowercase());
+						rest = r;
+					} else {
+						break;
+					}
+				}
+				if rest.starts_with(|c: char| !c.is_whitespace() && c != '>') {
+					return Err(err(&format!("unexpected '{}'", rest.chars().next().unwrap_or(' '))));
+				}
+				parts.push((combinator, compound));
+				combinator = Combinator::Descendant;
+				rest = rest.trim_start();
+			}
+			alternatives.push(parts);
+			match next {
+				Some(next) => remaining = next,
+				None => break,
+			}
+		}
+		Ok(Selector(alternatives))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn doc(html: &str) -> Document {
+		Document::parse(html.to_string())
+	}
+
+	#[test]
+	fn nested_tags() {
+		let d = doc("<html><body><div id='a'><p>one <b>two</b></p><p>three</p></div></body></html>");
+		assert_eq!(d.extract_text("#a").unwrap().as_deref(), Some("one two three"));
+		assert_eq!(d.extract_text("div p b").unwrap().as_deref(), Some("two"));
+	}
+
+	#[test]
+	fn unclosed_tags_close_implicitly() {
+		let d = doc("<ul><li>1<li>2<li>3</ul><p>after<p>last");
+		assert_eq!(d.extract_text("li:last-child").unwrap().as_deref(), Some("3"));
+		assert_eq!(d.extract_text("ul > li:nth-child(2)").unwrap().as_deref(), Some("2"));
+		assert_eq!(d.extract_text("p:last-child").unwrap().as_deref(), Some("last"));
+		// stray end tags and never closed elements don't break parsing
+		let d = doc("<div></span><section><em>deep");
+		assert_eq!(d.extract_text("div section em").unwrap().as_deref(), Some("deep"));
+	}
+
+	#[test]
+	fn void_and_raw_text_elements() {
+		let d = doc("<div><br><img src=x.png><span>a</span><script>if (1 < 2) { '<span>b</span>' }</script></div>");
+		assert_eq!(d.extract_text("div > span").unwrap().as_deref(), Some("a"));
+		assert_eq!(d.extract_text("div").unwrap().as_deref(), Some("a"));
+		assert_eq!(d.extract_text("img @src").unwrap().as_deref(), Some("x.png"));
+	}
+
+	#[test]
+	fn entities() {
+		assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;&apos;&#65;&#x42;&nbsp;"), "a & b <c> \"'AB ");
+		assert_eq!(decode_entities("AT&T &unknown; & &#xZZ;"), "AT&T &unknown; & &#xZZ;");
+		let d = doc("<p title=\"&lt;3\">1&#44;234</p>");
+		assert_eq!(d.extract("p").unwrap(), Some(1234.0));
+		assert_eq!(d.extract_text("p @title").unwrap().as_deref(), Some("<3"));
+	}
+
+	#[test]
+	fn attribute_quoting() {
+		let attrs = parse_attrs(r#" a="x > y" b='single "inner"' c=bare d e = "spaced" /"#);
+		assert_eq!(attrs, vec![
+			("a".to_string(), "x > y".to_string()),
+			("b".to_string(), "single \"inner\"".to_string()),
+			("c".to_string(), "bare".to_string()),
+			("d".to_string(), "".to_string()),
+			("e".to_string(), "spaced".to_string()),
+		]);
+		let d = doc(r#"<span data-v="a > b" CLASS='x y'>ok</span>"#);
+		assert_eq!(d.extract_text("span.x.y[data-v='a > b']").unwrap().as_deref(), Some("ok"));
+	}
+
+	#[test]
+	fn nth_child() {
+		let d = doc("<table><tr><td>a</td><td>b</td><td>c</td></tr><tr><td>d</td><td>e</td></tr></table>");
+		assert_eq!(d.extract_text("td:first-child").unwrap().as_deref(), Some("a"));
+		assert_eq!(d.extract_text("td:nth-child(2)").unwrap().as_deref(), Some("b"));
+		assert_eq!(d.extract_text("tr:nth-child(2) td:last-child").unwrap().as_deref(), Some("e"));
+		assert_eq!(d.extract_text("td:nth-child(4)").unwrap(), None);
+		assert!(d.extract_text("td:nth-child(x)").is_err());
+		assert!(d.extract_text("td:hover").is_err());
+	}
+
+	#[test]
+	fn combinators() {
+		let d = doc("<div class=o><section><span>deep</span></section><span>direct</span></div>");
+		assert_eq!(d.extract_text(".o span").unwrap().as_deref(), Some("deep"));
+		assert_eq!(d.extract_text(".o > span").unwrap().as_deref(), Some("direct"));
+		assert_eq!(d.extract_text("div>section>span").unwrap().as_deref(), Some("deep"));
+		assert_eq!(d.extract_text("em, .o > span").unwrap().as_deref(), Some("direct"));
+		assert_eq!(d.extract_text("section > div").unwrap(), None);
+	}
+
+	#[test]
+	fn query_splits_regex_at_first_bar_outside_selector() {
+		let d = doc(r#"<p data-x="a|b,c">status: none</p><i>42 items</i>"#);
+		assert_eq!(d.extract_text("p | (\\d+)|(none)").unwrap().as_deref(), Some("none"));
+		assert_eq!(d.extract_text("p[data-x='a|b,c'] @data-x").unwrap().as_deref(), Some("a|b,c"));
+		assert_eq!(d.extract_text("p[data-x='a|b,c'] | ^status: (\\w+)$").unwrap().as_deref(), Some("none"));
+		assert_eq!(d.extract("i | zzz|(\\d+)").unwrap(), Some(42.0));
+		assert_eq!(d.extract("i | (\\d+) items|none").unwrap(), Some(42.0));
+	}
+
+	#[test]
+	fn quoted_spaces_and_ats_are_not_attributes() {
+		let d = doc("<span title='a @b'>7</span><span title='c d' data-n='8'>x</span>");
+		assert_eq!(d.extract("span[title='a @b']").unwrap(), Some(7.0));
+		assert_eq!(d.extract_text("span[title=\"a @b\"] @title").unwrap().as_deref(), Some("a @b"));
+		assert_eq!(d.extract("span[title='c d'] @data-n").unwrap(), Some(8.0));
+	}
+
+	#[test]
+	fn bad_selectors_fail() {
+		let d = doc("<p>1</p>");
+		assert!(d.extract("p[x").is_err());
+		assert!(d.extract("p,").is_err());
+		assert!(d.extract("p $").is_err());
+		assert!(d.extract("p | (").is_err());
+	}
+}
@@ -0,0 +1,106 @@
+/// Selector for an object field, quoted and escaped the way jql expects it. jql only unescapes
+/// quotes, so keys with backslashes or control characters can't be selected at all.
+pub fn jql_key(key: &str) -> Option<String> {
+	if key.chars().any(|c| c == '\\' || c.is_control()) {
+		return None;
+	}
+	Some(format!("\"{}\"", key.replace('"', "\\\"")))
+}
+
+/// Selector for an array element
+pub fn jql_index(index: usize) -> String {
+	format!("[{}]", index)
+}
+
+/// Append a selector to a jql path, empty path being the payload root
+pub fn jql_join(path: &str, selector: &str) -> String {
+	if path.is_empty() {
+		selector.to_string()
+	} else {
+		format!("{}.{}", path, selector)
+	}
+}
+
+/// A number found walking a payload, with the jql query selecting it
+pub struct NumericLeaf {
+	pub query: String,
+	pub name: String,
+	pub value: f64,
+}
+
+/// Walk a payload collecting every numeric value, in document order
+pub fn numeric_leaves(value: &serde_json::Value) -> Vec<NumericLeaf> {
+	let mut out = vec![];
+	walk(value, "", "", &mut out);
+	out
+}
+
+fn walk(value: &serde_json::Value, query: &str, name: &str, out: &mut Vec<NumericLeaf>) {
+	match value {
+		serde_json::Value::Object(map) => {
+			for (key, v) in map.iter() {
+				let Some(selector) = jql_key(key) else { continue };
+				let child_name = if name.is_empty() { key.clone() } else { format!("{}.{}", name, key) };
+				walk(v, &jql_join(query, &selector), &child_name, out);
+			}
+		},
+		serde_json::Value::Array(arr) => {
+			for (i, v) in arr.iter().enumerate() {
+				walk(v, &jql_join(query, &jql_index(i)), &format!("{}{}", name, jql_index(i)), out);
+			}
+		},
+		serde_json::Value::Number(n) => {
+			if let Some(value) = n.as_f64() {
+				out.push(NumericLeaf { query: query.to_string(), name: name.to_string(), value });
+			}
+		},
+		_ => {},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn leaves(value: serde_json::Value) -> Vec<(String, String, f64)> {
+		numeric_leaves(&value).into_iter().map(|l| (l.query, l.name, l.value)).collect()
+	}
+
+	#[test]
+	fn nested_objects_and_arrays_are_walked() {
+		let found = leaves(serde_json::json!({
+			"cpu": {"load": [0.5, 1.5], "temp": 40},
+			"disks": [{"free": 10}, {"free": 20.5}],
+		}));
+		assert_eq!(found, vec![
+			(r#""cpu"."load".[0]"#.into(), "cpu.load[0]".into(), 0.5),
+			(r#""cpu"."load".[1]"#.into(), "cpu.load[1]".into(), 1.5),
+			(r#""cpu"."temp""#.into(), "cpu.temp".into(), 40.0),
+			(r#""disks".[0]."free""#.into(), "disks[0].free".into(), 10.0),
+			(r#""disks".[1]."free""#.into(), "disks[1].free".into(), 20.5),
+		]);
+		assert_eq!(leaves(serde_json::json!(3)), vec![("".into(), "".into(), 3.0)]);
+	}
+
+	#[test]
+	fn non_numeric_leaves_are_skipped() {
+		let found = leaves(serde_json::json!({
+			"name": "host", "up": true, "none": null, "tags": ["a", "b"], "empty": {}, "n": 1,
+		}));
+		assert_eq!(found, vec![(r#""n""#.into(), "n".into(), 1.0)]);
+	}
+
+	#[test]
+	fn keys_are_escaped() {
+		assert_eq!(jql_key("plain").unwrap(), r#""plain""#);
+		assert_eq!(jql_key(r#"say "hi""#).unwrap(), r#""say \"hi\"""#);
+		assert!(jql_key("back\\slash").is_none());
+		assert!(jql_key("new\nline").is_none());
+		let payload = serde_json::json!({"a.b": {"c d": 1}, "say \"hi\"": 2, "back\\slash": 3});
+		let found = numeric_leaves(&payload);
+		assert_eq!(found.len(), 2);
+		for leaf in found {
+			assert_eq!(jql::walker(&payload, &leaf.query).unwrap(), serde_json::json!(leaf.value as i64), "{}", leaf.query);
+		}
+	}
+}
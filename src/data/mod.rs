@@ -1,6 +1,12 @@
 pub mod entities;
+pub mod json;
+pub mod html;
 
-use std::num::ParseFloatError;
+use std::{num::ParseFloatError, collections::HashSet, path::PathBuf};
+
+use migration::{Migrator, MigratorTrait, SchemaManager};
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, sea_query::{Alias, Query}};
+use tracing::{info, warn};
 
 #[derive(Debug)]
 pub enum FetchError {
@@ -9,6 +15,105 @@ pub enum FetchError {
 	JQLError(String),
 	ParseFloatError(ParseFloatError),
 	DbError(sea_orm::DbErr),
+	NotFound(String),
+	Throttled(i64),
+	SqlError(sqlx::Error),
+	SelectorError(String),
+	AuthError(String),
+	ChainError(String),
+}
+
+/// SQLite database in user data dir, used when no database is given. File is created if missing.
+pub fn default_uri() -> String {
+	let dir = dirs::data_dir().unwrap_or(PathBuf::from("."));
+	if let Err(e) = std::fs::create_dir_all(&dir) {
+		warn!(target: "database", "Could not create data directory '{}': {:?}", dir.display(), e);
+	}
+	format!("sqlite://{}?mode=rwc", dir.join("dashboard.db").display())
+}
+
+// where applied migrations are tracked, created by first migration run
+const MIGRATIONS_TABLE : &str = "seaql_migrations";
+
+/// Versions of migrations applied on database, or None if it was never migrated. Unlike
+/// `Migrator` methods, this never creates the tracking table.
+async fn applied_migrations(db: &DatabaseConnection) -> Result<Option<HashSet<String>>, DbErr> {
+	if !SchemaManager::new(db).has_table(MIGRATIONS_TABLE).await? {
+		return Ok(None);
+	}
+	let query = Query::select()
+		.column(Alias::new("version"))
+		.from(Alias::new(MIGRATIONS_TABLE))
+		.to_owned();
+	let rows = db.query_all(db.get_database_backend().build(&query)).await?;
+	rows.iter()
+		.map(|row| row.try_get::<String>("", "version"))
+		.collect::<Result<HashSet<String>, DbErr>>()
+		.map(Some)
+}
+
+/// Connect to database and apply pending migrations. When `read_only`, nothing is ever written
+/// and outdated databases are refused instead.
+pub async fn connect(uri: &str, read_only: bool) -> Result<DatabaseConnection, DbErr> {
+	let db = Database::connect(uri).await?;
+	if read_only {
+		let pending = match applied_migrations(&db).await? {
+			Some(applied) => Migrator::migrations().iter().filter(|m| !applied.contains(m.name())).count(),
+			None => Migrator::migrations().len(),
+		};
+		if pending > 0 {
+			return Err(DbErr::Custom(format!(
+				"database schema is {} migrations behind and read-only mode won't upgrade it, run `dashboard migrate up --db <uri>` first",
+				pending
+			)));
+		}
+		return Ok(db);
+	}
+	let pending = Migrator::get_pending_migrations(&db).await?.len();
+	if pending > 0 {
+		info!(target: "database", "Applying {} pending migrations", pending);
+		Migrator::up(&db, None).await?;
+	}
+	Ok(db)
+}
+
+/// Every migration known to this binary and whether it's applied on database
+pub async fn migration_status(db: &DatabaseConnection) -> Result<Vec<(String, bool)>, DbErr> {
+	let applied = applied_migrations(db).await?.unwrap_or_default();
+	Ok(Migrator::migrations()
+		.iter()
+		.map(|m| (m.name().to_string(), applied.contains(m.name())))
+		.collect())
+}
+
+/// What fetching a source produced, which metrics extract their values from
+#[derive(Debug, Clone)]
+pub enum Payload {
+	Json(serde_json::Value),
+	Html(html::Document),
+}
+
+impl Payload {
+	/// Value found by a metric query, as text: strings are returned without quotes
+	pub fn lookup(&self, query: &str) -> Result<Option<String>, FetchError> {
+		match self {
+			Payload::Json(value) => match jql::walker(value, query)? {
+				serde_json::Value::Null => Ok(None),
+				serde_json::Value::String(s) => Ok(Some(s)),
+				v => Ok(Some(v.to_string())),
+			},
+			Payload::Html(doc) => doc.extract_text(query),
+		}
+	}
+}
+
+impl std::fmt::Display for Payload {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Payload::Json(value) => write!(f, "{}", value),
+			Payload::Html(doc) => write!(f, "html page ({} bytes)", doc.source.len()),
+		}
+	}
 }
 
 impl From<reqwest::Error> for FetchError {
@@ -32,8 +137,83 @@ impl From<ParseFloatError> for FetchError {
 		FetchError::ParseFloatError(e)
 	}
 }
+impl From<sqlx::Error> for FetchError {
+	fn from(e: sqlx::Error) -> Self {
+		FetchError::SqlError(e)
+	}
+}
 impl From<sea_orm::DbErr> for FetchError {
 	fn from(e: sea_orm::DbErr) -> Self {
 		FetchError::DbError(e)
 	}
 }
+
+/// Empty in-memory SQLite database with a table for each entity, for tests
+#[cfg(test)]
+pub async fn test_db() -> DatabaseConnection {
+	use sea_orm::{ConnectOptions, Schema};
+	let mut opts = ConnectOptions::new("sqlite::memory:".into());
+	opts.max_connections(1).min_connections(1).sqlx_logging(false); // each connection would get its own db
+	let db = Database::connect(opts).await.unwrap();
+	let schema = Schema::new(db.get_database_backend());
+	let tables = [
+		schema.create_table_from_entity(entities::sources::Entity),
+		schema.create_table_from_entity(entities::metrics::Entity),
+		schema.create_table_from_entity(entities::points::Entity),
+		schema.create_table_from_entity(entities::rollups::Entity),
+		schema.create_table_from_entity(entities::panels::Entity),
+		schema.create_table_from_entity(entities::panel_metric::Entity),
+		schema.create_table_from_entity(entities::payloads::Entity),
+	];
+	for table in tables {
+		db.execute(db.get_database_backend().build(&table)).await.unwrap();
+	}
+	db
+}
+
+/// Store a source with id 1 and one of its metrics, adjusted by `setup`, for tests
+#[cfg(test)]
+pub async fn test_metric(db: &DatabaseConnection, setup: impl FnOnce(&mut entities::metrics::Model)) -> entities::metrics::Model {
+	use sea_orm::{EntityTrait, IntoActiveModel};
+	if entities::sources::Entity::find_by_id(1).one(db).await.unwrap().is_none() {
+		let source = entities::sources::Model { id: 1, name: "source".into(), ..Default::default() };
+		entities::sources::Entity::insert(source.into_active_model()).exec(db).await.unwrap();
+	}
+	let mut metric = entities::metrics::Model { id: 1, source_id: 1, name: "metric".into(), ..Default::default() };
+	setup(&mut metric);
+	entities::metrics::Entity::insert(metric.clone().into_active_model()).exec(db).await.unwrap();
+	metric
+}
+
+/// SQLite database in a fresh file under temp dir, for tests needing to connect more than once
+#[cfg(test)]
+pub fn test_db_uri(name: &str) -> String {
+	let path = std::env::temp_dir().join(format!("dashboard-{}-{}.db", name, std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	format!("sqlite://{}?mode=rwc", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn read_only_connect_leaves_unmigrated_db_alone() {
+		let uri = test_db_uri("unmigrated");
+		let err = connect(&uri, true).await.unwrap_err();
+		assert!(err.to_string().contains("migrations behind"), "{}", err);
+		let db = Database::connect(&uri).await.unwrap();
+		assert!(!SchemaManager::new(&db).has_table(MIGRATIONS_TABLE).await.unwrap());
+		assert!(migration_status(&db).await.unwrap().iter().all(|(_, applied)| !applied));
+		assert!(!SchemaManager::new(&db).has_table(MIGRATIONS_TABLE).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn read_only_connect_accepts_migrated_db() {
+		let uri = test_db_uri("migrated");
+		connect(&uri, false).await.unwrap();
+		let db = connect(&uri, true).await.unwrap();
+		assert!(migration_status(&db).await.unwrap().iter().all(|(_, applied)| *applied));
+	}
+}
+
@@ -1,6 +1,7 @@
 pub mod panel;
 pub mod source;
 pub mod metric;
+pub mod payload;
 
 mod scaffold;
 
@@ -9,12 +10,9 @@ use eframe::egui::{CentralPanel, Context, SidePanel, TopBottomPanel, Window};
 use tokio::sync::{watch, mpsc};
 use tracing::error;
 
-use crate::{data::entities, worker::{visualizer::AppStateView, BackgroundAction}};
-use panel::main_content;
-use scaffold::{
-	// confirmation_popup_delete_metric, confirmation_popup_delete_source, footer,
-	header,
-};
+use crate::{data::entities, worker::{visualizer::AppStateView, BackgroundAction, DeleteTarget}};
+use panel::{main_content, RangeSelection};
+use scaffold::{header, confirmation_popup_delete, DeletingModel};
 use source::source_panel_ui;
 
 use self::scaffold::{footer, EditingModel, popup_edit_ui};
@@ -37,6 +35,8 @@ pub struct App {
 
 	edit: bool,
 	editing: Vec<EditingModel>,
+	deleting: Vec<DeletingModel>,
+	selection: Option<RangeSelection>,
 	sidebar: bool,
 	_padding: bool,
 	// windows: Vec<Window<'open>>,
@@ -67,6 +67,8 @@ impl App {
 			last_redraw: 0,
 			edit: false,
 			editing: vec![],
+			deleting: vec![],
+			selection: None,
 			sidebar: true,
 			_padding: false,
 			// windows: vec![],
@@ -124,7 +126,15 @@ impl eframe::App for App {
 		for m in self.editing.iter_mut() {
 			Window::new(m.id_repr())
 				.default_width(150.0)
-				.show(ctx, |ui| popup_edit_ui(ui, m, &self.view.sources.borrow(), &self.view.metrics.borrow()));
+				.show(ctx, |ui| popup_edit_ui(ui, m, &self.view.sources.borrow(), &self.view.metrics.borrow(), &self.view.payloads.borrow()));
+		}
+
+		let payload_requests : Vec<entities::sources::Model> = self.editing
+			.iter_mut()
+			.filter_map(|m| m.payload_request(&self.view.sources.borrow()))
+			.collect();
+		for source in payload_requests {
+			self.op(BackgroundAction::FetchPayload { source });
 		}
 
 		if self.sidebar {
@@ -161,6 +171,41 @@ impl eframe::App for App {
 			}
 		}
 
+		let delete_requests : Vec<(DeleteTarget, String)> = self.editing
+			.iter()
+			.filter_map(|m| m.delete_request())
+			.collect();
+		for (target, name) in delete_requests {
+			let (model, count) = DeletingModel::new(target, name);
+			self.deleting.push(model);
+			self.op(count);
+		}
+
+		let transfers : Vec<BackgroundAction> = self.editing
+			.iter_mut()
+			.filter_map(|m| m.transfer_request())
+			.collect();
+		for op in transfers {
+			self.op(op);
+		}
+
 		self.editing.retain(|v| v.modifying());
+
+		for m in self.deleting.iter_mut() {
+			Window::new(m.id_repr())
+				.default_width(200.0)
+				.show(ctx, |ui| confirmation_popup_delete(ui, m));
+		}
+
+		for m in self.deleting.iter() {
+			if let Some(op) = m.to_msg() {
+				if let BackgroundAction::Delete { target: DeleteTarget::Panel(id), .. } = op {
+					self.panels.retain(|p| p.id != id); // or saving all panels would bring it back
+				}
+				self.op(op);
+			}
+		}
+
+		self.deleting.retain(|m| !m.finished());
 	}
 }
@@ -1,12 +1,14 @@
 use chrono::{Local, Utc};
 use eframe::{egui::{
-	plot::{Corner, GridMark, Legend, Line, Plot},
-	Ui, ScrollArea, collapsing_header::CollapsingState, Context, Layout, Slider, DragValue,
+	plot::{Corner, GridMark, Legend, Line, Plot, VLine},
+	Ui, ScrollArea, collapsing_header::CollapsingState, Context, Layout, Slider, DragValue, Button, Color32,
 }, emath::Vec2};
+use tokio::sync::oneshot::{self, error::TryRecvError};
 
 use crate::util::{timestamp_to_str, unpack_color};
 use crate::gui::App;
 use crate::data::entities;
+use crate::worker::{BackgroundAction, RangeEdit, RangeCount};
 
 use super::scaffold::EditingModel;
 
@@ -14,6 +16,7 @@ pub fn main_content(app: &mut App, ctx: &Context, ui: &mut Ui) {
 	let panel_metric = app.view.panel_metric.borrow();
 	let metrics = app.view.metrics.borrow();
 	let points = app.view.points.borrow();
+	let mut ops = vec![];
 	ScrollArea::vertical().show(ui, |ui| {
 		ui.separator();
 		if app.edit {
@@ -26,7 +29,13 @@ pub fn main_content(app: &mut App, ctx: &Context, ui: &mut Ui) {
 				.show_header(ui, |ui| {
 					panel_title_ui_edit(ui, &mut panel, &mut app.editing, &metrics, &panel_metric);
 				})
-				.body(|ui| panel_body_ui(ui, panel, &metrics, &points, &panel_metric));
+				.body(|ui| {
+					let picking = app.selection.as_mut().filter(|s| s.panel_id == panel.id);
+					panel_body_ui(ui, panel, &metrics, &points, &panel_metric, picking);
+					if let Some(op) = range_selection_ui(ui, panel, &mut app.selection, &metrics, &panel_metric) {
+						ops.push(op);
+					}
+				});
 				ui.separator();
 			}
 		} else {
@@ -39,13 +48,58 @@ pub fn main_content(app: &mut App, ctx: &Context, ui: &mut Ui) {
 				.show_header(ui, |ui| {
 					panel_title_ui(ui, &panel, &mut app.editing, &metrics, &panel_metric);
 				})
-				.body(|ui| panel_body_ui(ui, panel, &metrics, &points, &panel_metric));
+				.body(|ui| panel_body_ui(ui, panel, &metrics, &points, &panel_metric, None));
 				ui.separator();
 			}
 		}
 	});
+	for op in ops {
+		app.op(op);
+	}
+}
+
+/// X range being picked on a panel plot, to delete or flag points of some of its metrics
+pub struct RangeSelection {
+	panel_id: i64,
+	from: Option<f64>,
+	to: Option<f64>,
+	metrics: Vec<i64>,
+	count: Option<Result<RangeCount, String>>,
+	count_rx: Option<oneshot::Receiver<Result<RangeCount, String>>>,
+}
+
+impl RangeSelection {
+	fn new(panel_id: i64, metrics: Vec<i64>) -> Self {
+		RangeSelection { panel_id, metrics, from: None, to: None, count: None, count_rx: None }
+	}
+
+	/// Ordered range, once both ends have been picked
+	fn bounds(&self) -> Option<(f64, f64)> {
+		match (self.from, self.to) {
+			(Some(a), Some(b)) => Some((a.min(b), a.max(b))),
+			_ => None,
+		}
+	}
+
+	/// First click sets range start, second one its end, a third one starts over
+	fn click(&mut self, x: f64) {
+		if self.from.is_none() || self.to.is_some() {
+			self.from = Some(x);
+			self.to = None;
+		} else {
+			self.to = Some(x);
+		}
+		self.invalidate();
+	}
+
+	/// Forget previewed count, range or metrics changed
+	fn invalidate(&mut self) {
+		self.count = None;
+		self.count_rx = None;
+	}
 }
 
+
 pub fn panel_title_ui(
 	ui: &mut Ui,
 	panel: &entities::panels::Model,
@@ -126,6 +180,7 @@ pub fn panel_body_ui(
 	metrics: &Vec<entities::metrics::Model>,
 	points: &Vec<entities::points::Model>,
 	panel_metric: &Vec<entities::panel_metric::Model>,
+	selection: Option<&mut RangeSelection>,
 ) {
 	let mut p = Plot::new(format!("plot-{}", panel.name))
 		.height(panel.height as f32)
@@ -210,6 +265,9 @@ pub fn panel_body_ui(
 						if x.len() > 0 { x[x.len()-1] } else { [0.0, 0.0 ]}
 					}).collect();
 			}
+			if metric.on_change {
+				values = step_values(values, metric.heartbeat, max_x.min(now));
+			}
 			lines.push(
 				Line::new(values)
 					.name(metric.name.as_str())
@@ -218,11 +276,130 @@ pub fn panel_body_ui(
 		}
 	}
 
-	p.show(ui, |plot_ui| {
+	let clicked = p.show(ui, |plot_ui| {
 		for line in lines {
 			plot_ui.line(line);
 		}
+		if let Some(selection) = &selection {
+			for x in [selection.from, selection.to].into_iter().flatten() {
+				plot_ui.vline(VLine::new(x).color(Color32::RED));
+			}
+		}
+		if plot_ui.plot_clicked() { plot_ui.pointer_coordinate() } else { None }
+	}).inner;
+
+	if let (Some(selection), Some(clicked)) = (selection, clicked) {
+		selection.click(clicked.x);
+	}
+}
+
+/// Controls below a panel plot to pick an x range and metrics, preview how many points fall in
+/// there and delete or flag them. Returns an operation for the background worker, if any.
+fn range_selection_ui(
+	ui: &mut Ui,
+	panel: &entities::panels::Model,
+	selection: &mut Option<RangeSelection>,
+	metrics: &[entities::metrics::Model],
+	panel_metric: &[entities::panel_metric::Model],
+) -> Option<BackgroundAction> {
+	let metric_ids : Vec<i64> = panel_metric.iter().filter(|x| x.panel_id == panel.id).map(|x| x.metric_id).collect();
+	let Some(sel) = selection.as_mut().filter(|s| s.panel_id == panel.id) else {
+		if ui.small_button("✂ select range").on_hover_text("click twice on plot to pick points to delete or flag").clicked() {
+			*selection = Some(RangeSelection::new(panel.id, metric_ids));
+		}
+		return None;
+	};
+
+	if let Some(count_rx) = &mut sel.count_rx {
+		match count_rx.try_recv() {
+			Ok(count) => sel.count = Some(count),
+			Err(TryRecvError::Closed) => sel.count = Some(Err("worker stopped before answering".into())),
+			Err(TryRecvError::Empty) => {},
+		}
+		if sel.count.is_some() {
+			sel.count_rx = None;
+		}
+	}
+
+	let mut op = None;
+	let mut close = false;
+	ui.horizontal(|ui| {
+		match sel.bounds() {
+			Some((from, to)) => ui.label(format!("{} → {}", timestamp_to_str(from as i64, true, true), timestamp_to_str(to as i64, true, true))),
+			None if sel.from.is_some() => ui.label("click on plot to set range end"),
+			None => ui.label("click on plot to set range start"),
+		};
+		ui.separator();
+		for metric in metrics.iter().filter(|m| metric_ids.contains(&m.id)) {
+			let mut picked = sel.metrics.contains(&metric.id);
+			if ui.checkbox(&mut picked, metric.name.as_str()).changed() {
+				if picked {
+					sel.metrics.push(metric.id);
+				} else {
+					sel.metrics.retain(|id| *id != metric.id);
+				}
+				sel.invalidate();
+			}
+		}
+		ui.with_layout(Layout::right_to_left(eframe::emath::Align::Min), |ui| {
+			if ui.small_button("cancel").clicked() {
+				close = true;
+			}
+			let Some((from, to)) = sel.bounds() else { return };
+			match &sel.count {
+				Some(Ok(count)) => {
+					let edits = [
+						(RangeEdit::Delete, "delete", count.points + count.rollups > 0, "remove these points and rollups for good"),
+						(RangeEdit::Flag, "flag", count.flaggable(), "hide these points from plots, keeping them stored. Ranges with rollups can't be flagged"),
+					];
+					for (edit, label, enabled, hint) in edits {
+						if ui.add_enabled(enabled, Button::new(label).small()).on_hover_text(hint).clicked() {
+							op = Some(BackgroundAction::EditRange { metrics: sel.metrics.clone(), from, to, edit });
+							close = true;
+						}
+					}
+					if count.rollups > 0 {
+						ui.colored_label(Color32::RED, format!("{} points, {} rollups in range", count.points, count.rollups));
+					} else {
+						ui.colored_label(Color32::RED, format!("{} points in range", count.points));
+					}
+				},
+				Some(Err(e)) => { ui.colored_label(Color32::RED, format!("⚠ could not count points: {}", e)); },
+				None if sel.count_rx.is_some() => { ui.label("counting points..."); },
+				None => {
+					if ui.add_enabled(!sel.metrics.is_empty(), Button::new("preview").small()).clicked() {
+						let (reply, count_rx) = oneshot::channel();
+						sel.count_rx = Some(count_rx);
+						op = Some(BackgroundAction::CountRange { metrics: sel.metrics.clone(), from, to, reply });
+					}
+				},
+			}
+		});
 	});
+
+	if close {
+		*selection = None;
+	}
+	op
+}
+
+/// Turn points into a step line, holding each value until the next one. Last value is held
+/// until `until`, but no longer than heartbeat since a point should have arrived by then.
+fn step_values(values: Vec<[f64;2]>, heartbeat: i32, until: f64) -> Vec<[f64;2]> {
+	let mut out = Vec::with_capacity(values.len() * 2);
+	for (i, v) in values.iter().enumerate() {
+		if i > 0 {
+			out.push([v[0], values[i-1][1]]);
+		}
+		out.push(*v);
+	}
+	if let Some(last) = values.last() {
+		let end = if heartbeat > 0 { until.min(last[0] + heartbeat as f64 * 60.0) } else { until };
+		if end > last[0] {
+			out.push([end, last[1]]);
+		}
+	}
+	out
 }
 
 fn avg_value(values: &[[f64;2]]) -> [f64;2] {
@@ -237,3 +414,26 @@ fn avg_value(values: &[[f64;2]]) -> [f64;2] {
 		y / values.len() as f64,
 	];
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn step_values_hold_each_value_until_next() {
+		let steps = step_values(vec![[0.0, 1.0], [10.0, 2.0], [20.0, 3.0]], 0, 25.0);
+		assert_eq!(steps, vec![[0.0, 1.0], [10.0, 1.0], [10.0, 2.0], [20.0, 2.0], [20.0, 3.0], [25.0, 3.0]]);
+	}
+
+	#[test]
+	fn step_values_hold_last_value_up_to_heartbeat() {
+		let steps = step_values(vec![[0.0, 1.0]], 1, 1000.0);
+		assert_eq!(steps, vec![[0.0, 1.0], [60.0, 1.0]]);
+		let steps = step_values(vec![[0.0, 1.0]], 1, 30.0);
+		assert_eq!(steps, vec![[0.0, 1.0], [30.0, 1.0]]);
+		// nothing to hold when last point is already past until
+		assert_eq!(step_values(vec![[50.0, 1.0]], 0, 40.0), vec![[50.0, 1.0]]);
+		assert!(step_values(vec![], 1, 40.0).is_empty());
+	}
+}
+
@@ -0,0 +1,45 @@
+use eframe::egui::{Ui, CollapsingHeader};
+use serde_json::Value;
+
+use crate::data::json::{jql_join, jql_key, jql_index};
+
+/// Show a json payload as a collapsible tree. Clicking on a numeric leaf stores its jql path in `pick`
+pub fn payload_tree_ui(ui: &mut Ui, value: &Value, path: &str, pick: &mut Option<String>) {
+	match value {
+		Value::Object(map) => {
+			for (key, v) in map.iter() {
+				match jql_key(key) {
+					Some(selector) => payload_node_ui(ui, key, &jql_join(path, &selector), v, pick),
+					None => { ui.label(format!("{}: (key can't be queried)", key)); },
+				}
+			}
+		},
+		Value::Array(arr) => {
+			for (i, v) in arr.iter().enumerate() {
+				payload_node_ui(ui, &jql_index(i), &jql_join(path, &jql_index(i)), v, pick);
+			}
+		},
+		_ => payload_node_ui(ui, "", path, value, pick),
+	}
+}
+
+fn payload_node_ui(ui: &mut Ui, label: &str, path: &str, value: &Value, pick: &mut Option<String>) {
+	match value {
+		Value::Object(map) => {
+			CollapsingHeader::new(format!("{} {{{}}}", label, map.len()))
+				.id_source(path)
+				.show(ui, |ui| payload_tree_ui(ui, value, path, pick));
+		},
+		Value::Array(arr) => {
+			CollapsingHeader::new(format!("{} [{}]", label, arr.len()))
+				.id_source(path)
+				.show(ui, |ui| payload_tree_ui(ui, value, path, pick));
+		},
+		Value::Number(n) => {
+			if ui.link(format!("{}: {}", label, n)).on_hover_text(path).clicked() {
+				*pick = Some(path.to_string());
+			}
+		},
+		_ => { ui.label(format!("{}: {}", label, value)); },
+	}
+}
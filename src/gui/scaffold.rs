@@ -1,57 +1,85 @@
-use eframe::{Frame, egui::{collapsing_header::CollapsingState, Context, Ui, Layout, ScrollArea, global_dark_light_mode_switch, TextEdit, Checkbox, Slider, ComboBox, DragValue}, emath::Align};
-use sea_orm::{Set, Unchanged, ActiveValue::NotSet};
-use tokio::sync::watch;
+use std::collections::HashMap;
 
-use crate::{gui::App, data::entities, util::{unpack_color, repack_color}, worker::{BackgroundAction, AppStateView}};
+use eframe::{Frame, egui::{collapsing_header::CollapsingState, CollapsingHeader, Context, Ui, Layout, ScrollArea, global_dark_light_mode_switch, TextEdit, Checkbox, Slider, ComboBox, DragValue, Color32, Button, color::Hsva}, emath::Align};
+use sea_orm::{Set, Unchanged, ActiveValue::NotSet, Iterable};
+use tokio::sync::{watch, oneshot::{self, error::TryRecvError}};
 
-// TODO make this not super specific!
-pub fn _confirmation_popup_delete_metric(_app: &mut App, ui: &mut Ui, _metric_index: usize) {
-	ui.heading("Are you sure you want to delete this metric?");
-	ui.label("This will remove all its metrics and delete all points from archive. This action CANNOT BE UNDONE!");
-	ui.with_layout(Layout::top_down(Align::RIGHT), |ui| {
-		ui.horizontal(|ui| {
-			if ui.button("\n   yes   \n").clicked() {
-				// let store = app.data.storage.lock().expect("Storage Mutex poisoned");
-				// let mut metrics = app.data.metrics.write().expect("Metrics RwLock poisoned");
-				// store.delete_metric(metrics[metric_index].id).expect("Failed deleting metric");
-				// store.delete_values(metrics[metric_index].id).expect("Failed deleting values");
-				// metrics.remove(metric_index);
-				// app.deleting_metric = None;
-			}
-			if ui.button("\n   no    \n").clicked() {
-				// app.deleting_metric = None;
-			}
-		});
-	});
+use crate::{gui::App, data::{Payload, FetchError, entities::{self, sources::SourceKind, metrics::OutOfRange}, json::{numeric_leaves, NumericLeaf}}, util::{unpack_color, repack_color}, worker::{BackgroundAction, AppStateView, DeleteTarget, chain::check_cycles}};
+
+use super::payload::payload_tree_ui;
+
+/// Confirmation asked before deleting something, showing how many points would be destroyed
+pub struct DeletingModel {
+	target: DeleteTarget,
+	name: String,
+	count: Option<Result<u64, String>>,
+	count_rx: oneshot::Receiver<Result<u64, String>>,
+	with_points: bool,
+	confirmed: bool,
+	done: bool,
 }
 
-// TODO make this not super specific!
-pub fn _confirmation_popup_delete_source(_app: &mut App, ui: &mut Ui, _source_index: usize) {
-	ui.heading("Are you sure you want to delete this source?");
-	ui.label("This will remove all its metrics and delete all points from archive. This action CANNOT BE UNDONE!");
+impl DeletingModel {
+	/// Returns also the operation counting points, to be sent to background worker
+	pub fn new(target: DeleteTarget, name: String) -> (Self, BackgroundAction) {
+		let (reply, count_rx) = oneshot::channel();
+		(
+			DeletingModel { target, name, count: None, count_rx, with_points: true, confirmed: false, done: false },
+			BackgroundAction::CountPoints { target, reply },
+		)
+	}
+
+	pub fn id_repr(&self) -> String {
+		format!("delete {} '{}'", self.target.label(), self.name)
+	}
+
+	pub fn finished(&self) -> bool {
+		self.done
+	}
+
+	/// Delete operation, once user confirmed
+	pub fn to_msg(&self) -> Option<BackgroundAction> {
+		if self.confirmed {
+			Some(BackgroundAction::Delete { target: self.target, points: self.with_points })
+		} else {
+			None
+		}
+	}
+}
+
+pub fn confirmation_popup_delete(ui: &mut Ui, model: &mut DeletingModel) {
+	if model.count.is_none() {
+		match model.count_rx.try_recv() {
+			Ok(count) => model.count = Some(count),
+			Err(TryRecvError::Closed) => model.count = Some(Err("worker stopped before answering".into())),
+			Err(TryRecvError::Empty) => {},
+		}
+	}
+	ui.heading(format!("Are you sure you want to delete this {}?", model.target.label()));
+	match model.target {
+		DeleteTarget::Panel(_) => { ui.label("Metrics shown will be kept, no points are destroyed."); },
+		DeleteTarget::Source(_) => { ui.label("This will remove all its metrics and archived payloads. This action CANNOT BE UNDONE!"); },
+		DeleteTarget::Metric(_) => { ui.label("This will remove it from all panels. This action CANNOT BE UNDONE!"); },
+	}
+	if !matches!(model.target, DeleteTarget::Panel(_)) {
+		ui.checkbox(&mut model.with_points, "delete points too");
+		match &model.count {
+			Some(Ok(count)) if model.with_points => ui.colored_label(Color32::RED, format!("{} points will be destroyed", count)),
+			Some(Ok(count)) => ui.label(format!("{} points will be left orphaned", count)),
+			Some(Err(e)) => ui.colored_label(Color32::RED, format!("⚠ could not count points: {}", e)),
+			None => ui.label("counting points..."),
+		};
+	}
+	// don't delete blindly, user must know what will be lost
+	let counted = matches!(model.count, Some(Ok(_)));
 	ui.with_layout(Layout::top_down(Align::RIGHT), |ui| {
 		ui.horizontal(|ui| {
-			if ui.button("\n   yes   \n").clicked() {
-				// let store = app.data.storage.lock().expect("Storage Mutex poisoned");
-				// let mut sources = app.data.sources.write().expect("sources RwLock poisoned");
-				// let mut metrics = app.data.metrics.write().expect("Metrics RwLock poisoned");
-				// let mut to_remove = Vec::new();
-				// for j in 0..metrics.len() {
-				// 	if metrics[j].source_id == app.input_source.id {
-				// 		store.delete_values(metrics[j].id).expect("Failed deleting values");
-				// 		store.delete_metric(metrics[j].id).expect("Failed deleting Metric");
-				// 		to_remove.push(j);
-				// 	}
-				// }
-				// for index in to_remove {
-				// 	metrics.remove(index);
-				// }
-				// store.delete_source(sources[source_index].id).expect("Failed deleting source");
-				// sources.remove(source_index);
-				// app.deleting_source = None;
+			if ui.add_enabled(counted, Button::new("\n   yes   \n")).clicked() {
+				model.confirmed = true;
+				model.done = true;
 			}
 			if ui.button("\n   no    \n").clicked() {
-				// app.deleting_source = None;
+				model.done = true;
 			}
 		});
 	});
@@ -63,6 +91,10 @@ pub struct EditingModel {
 	new: bool,
 	valid: bool,
 	ready: bool,
+	delete: bool,
+	payload_requested: Option<i64>,
+	csv_path: String,
+	transfer: Option<BackgroundAction>,
 }
 
 impl EditingModel {
@@ -71,10 +103,29 @@ impl EditingModel {
 			EditingModelType::EditingPanel { panel: _, opts: _ } => "panel",
 			EditingModelType::EditingSource { source: _ } => "source",
 			EditingModelType::EditingMetric { metric: _ } => "metric",
+			EditingModelType::DiscoverSource { source: _, fields: _ } => "discover source",
 		};
 		format!("edit {} #{}", prefix, self.id)
 	}
 
+	/// What to delete, if user asked to delete edited model
+	pub fn delete_request(&self) -> Option<(DeleteTarget, String)> {
+		if !self.delete || self.new {
+			return None;
+		}
+		match &self.m {
+			EditingModelType::EditingPanel { panel, opts: _ } => Some((DeleteTarget::Panel(panel.id), panel.name.clone())),
+			EditingModelType::EditingSource { source } => Some((DeleteTarget::Source(source.id), source.name.clone())),
+			EditingModelType::EditingMetric { metric } => Some((DeleteTarget::Metric(metric.id), metric.name.clone())),
+			EditingModelType::DiscoverSource { source: _, fields: _ } => None,
+		}
+	}
+
+	/// CSV export or import user asked for, if any. Only returned once.
+	pub fn transfer_request(&mut self) -> Option<BackgroundAction> {
+		self.transfer.take()
+	}
+
 	pub fn should_fetch(&self) -> bool {
 		return self.ready && self.valid;
 	}
@@ -83,6 +134,34 @@ impl EditingModel {
 		return !self.ready;
 	}
 
+	/// Source whose payload should be fetched for previewing queries, if not already requested
+	pub fn payload_request(&mut self, sources: &[entities::sources::Model]) -> Option<entities::sources::Model> {
+		let source_id = match &self.m {
+			EditingModelType::EditingMetric { metric } => metric.source_id,
+			EditingModelType::DiscoverSource { source, fields: _ } => source.id,
+			_ => return None,
+		};
+		if self.payload_requested != Some(source_id) {
+			self.payload_requested = Some(source_id);
+			return sources.iter().find(|s| s.id == source_id).cloned();
+		}
+		None
+	}
+
+	pub fn make_discover(source: entities::sources::Model) -> EditingModel {
+		EditingModel {
+			id: source.id,
+			new: true,
+			m: EditingModelType::DiscoverSource { source, fields: vec![] },
+			valid: false,
+			ready: false,
+			delete: false,
+			payload_requested: None,
+			csv_path: String::new(),
+			transfer: None,
+		}
+	}
+
 	pub fn make_edit_panel(
 		panel: entities::panels::Model,
 		metrics: &Vec<entities::metrics::Model>,
@@ -101,6 +180,10 @@ impl EditingModel {
 			m: EditingModelType::EditingPanel { panel, opts },
 			valid: false,
 			ready: false,
+			delete: false,
+			payload_requested: None,
+			csv_path: String::new(),
+			transfer: None,
 		}
 	}
 
@@ -140,6 +223,23 @@ impl EditingModel {
 						interval: Set(source.interval),
 						last_update: Set(source.last_update),
 						position: Set(source.position),
+						ca_bundle: Set(source.ca_bundle.clone()),
+						client_cert: Set(source.client_cert.clone()),
+						client_key: Set(source.client_key.clone()),
+						insecure: Set(source.insecure),
+						proxy: Set(source.proxy.clone()),
+						record_unchanged: Set(source.record_unchanged),
+						rate_limit: Set(source.rate_limit),
+						kind: Set(source.kind),
+						body: Set(source.body.clone()),
+						token_url: Set(source.token_url.clone()),
+						client_id: Set(source.client_id.clone()),
+						client_secret: Set(source.client_secret.clone()),
+						scope: Set(source.scope.clone()),
+						archive: Set(source.archive),
+						archive_days: Set(source.archive_days),
+						headers: Set(source.headers.clone()),
+						rejected: NotSet, // counted by surveyors
 					}
 				},
 			EditingModelType::EditingMetric { metric } =>
@@ -151,8 +251,49 @@ impl EditingModel {
 						color: Set(metric.color),
 						query: Set(metric.query.clone()),
 						position: Set(metric.position),
+						valid_min: Set(metric.valid_min),
+						valid_max: Set(metric.valid_max),
+						out_of_range: Set(metric.out_of_range),
+						on_change: Set(metric.on_change),
+						deadband: Set(metric.deadband),
+						deadband_percent: Set(metric.deadband_percent),
+						heartbeat: Set(metric.heartbeat),
+						retention: Set(metric.retention),
 					}
 				},
+			EditingModelType::DiscoverSource { source, fields } => {
+				// spread hues evenly starting from a random one, so colors are distinct
+				let offset : f32 = rand::random();
+				// append after metrics source already has
+				let first = view.metrics.borrow()
+					.iter()
+					.filter(|m| m.source_id == source.id)
+					.map(|m| m.position + 1)
+					.max()
+					.unwrap_or(0);
+				BackgroundAction::CreateMetrics {
+					metrics: fields.iter()
+						.filter(|(_, selected)| *selected)
+						.enumerate()
+						.map(|(i, (leaf, _))| entities::metrics::ActiveModel {
+							id: NotSet,
+							name: Set(leaf.name.clone()),
+							source_id: Set(source.id),
+							color: Set(repack_color(Hsva::new((offset + 0.618034 * i as f32) % 1.0, 0.75, 0.9, 1.0).into())),
+							query: Set(leaf.query.clone()),
+							position: Set(first + i as i32),
+							valid_min: Set(None),
+							valid_max: Set(None),
+							out_of_range: Set(OutOfRange::Drop),
+							on_change: Set(false),
+							deadband: Set(0.0),
+							deadband_percent: Set(false),
+							heartbeat: Set(0),
+							retention: Set(None),
+						})
+						.collect(),
+				}
+			},
 		}
 	}
 }
@@ -161,7 +302,8 @@ impl From<entities::sources::Model> for EditingModel {
 	fn from(s: entities::sources::Model) -> Self {
 		EditingModel {
 			new: if s.id == 0 { true } else { false },
-			id: s.id, m: EditingModelType::EditingSource { source: s }, valid: false, ready: false,
+			id: s.id, m: EditingModelType::EditingSource { source: s }, valid: false, ready: false, delete: false, payload_requested: None,
+			csv_path: String::new(), transfer: None,
 		}
 	}
 }
@@ -170,7 +312,8 @@ impl From<entities::metrics::Model> for EditingModel {
 	fn from(m: entities::metrics::Model) -> Self {
 		EditingModel {
 			new: if m.id == 0 { true } else { false },
-			id: m.id, m: EditingModelType::EditingMetric { metric: m }, valid: false, ready: false,
+			id: m.id, m: EditingModelType::EditingMetric { metric: m }, valid: false, ready: false, delete: false, payload_requested: None,
+			csv_path: String::new(), transfer: None,
 		}
 	}
 }
@@ -179,7 +322,8 @@ impl From<entities::panels::Model> for EditingModel {
 	fn from(p: entities::panels::Model) -> Self {
 		EditingModel {
 			new: if p.id == 0 { true } else { false },
-			id: p.id, m: EditingModelType::EditingPanel { panel: p , opts: vec![] }, valid: false, ready: false,
+			id: p.id, m: EditingModelType::EditingPanel { panel: p , opts: vec![] }, valid: false, ready: false, delete: false, payload_requested: None,
+			csv_path: String::new(), transfer: None,
 		}
 	}
 }
@@ -188,13 +332,15 @@ pub enum EditingModelType {
 	EditingPanel  { panel : entities::panels::Model, opts: Vec<bool>  },
 	EditingSource { source: entities::sources::Model },
 	EditingMetric { metric: entities::metrics::Model },
+	DiscoverSource { source: entities::sources::Model, fields: Vec<(NumericLeaf, bool)> },
 }
 
 pub fn popup_edit_ui(
 	ui: &mut Ui,
 	model: &mut EditingModel,
 	sources: &Vec<entities::sources::Model>,
-	metrics: &Vec<entities::metrics::Model>
+	metrics: &Vec<entities::metrics::Model>,
+	payloads: &HashMap<i64, Payload>,
 ) {
 	match &mut model.m {
 		EditingModelType::EditingPanel { panel, opts } => {
@@ -252,11 +398,115 @@ pub fn popup_edit_ui(
 			ui.horizontal(|ui| {
 				ui.label("position");
 				ui.add(DragValue::new(&mut source.position).clamp_range(0..=1000));
+				ComboBox::from_id_source(format!("source-kind-{}", source.id))
+					.selected_text(source.kind.label())
+					.show_ui(ui, |ui| {
+						for kind in SourceKind::iter() {
+							ui.selectable_value(&mut source.kind, kind, kind.label());
+						}
+					});
 			});
 			TextEdit::singleline(&mut source.url)
-				.hint_text("url")
+				.hint_text(match source.kind {
+					SourceKind::Http => "url",
+					SourceKind::Sql => "connection string (sqlite://, postgres://)",
+					SourceKind::Uptime => "target (tcp://host:port, tls://host:port, http(s)://url)",
+					SourceKind::Html => "url of html page",
+				})
 				.show(ui);
+			if let Err(FetchError::ChainError(e)) = check_cycles(source, sources) {
+				ui.colored_label(Color32::RED, format!("⚠ {}", e));
+			}
 			ui.add(Slider::new(&mut source.interval, 1..=3600).text("interval"));
+			ui.horizontal(|ui| {
+				ui.checkbox(&mut source.archive, "archive payloads")
+					.on_hover_text("keep compressed raw payloads, to backfill metrics added later");
+				ui.add_enabled(source.archive, DragValue::new(&mut source.archive_days).clamp_range(0..=3650).suffix(" days"))
+					.on_hover_text("how long to keep archived payloads, 0 keeps them forever");
+			});
+			if source.kind == SourceKind::Sql {
+				TextEdit::multiline(&mut source.body)
+					.hint_text("SELECT count(*) AS pending FROM jobs")
+					.code_editor()
+					.desired_rows(3)
+					.show(ui);
+				ui.small("rows are addressed like json, e.g. [0].\"pending\"");
+			} else {
+				if source.kind == SourceKind::Html {
+					ui.small("metric queries are css selectors, e.g. span.price @content | ([0-9.]+)");
+				}
+				if source.kind != SourceKind::Uptime {
+					ui.checkbox(&mut source.record_unchanged, "record values when unchanged")
+						.on_hover_text("when server replies 304 Not Modified, store previous values again instead of skipping points");
+				} else {
+					ui.small("payload has \"up\", \"latency\" and, if applicable, \"status\" and \"cert_days\"");
+				}
+				CollapsingHeader::new("connection")
+					.id_source(format!("source-connection-{}", source.id))
+					.show(ui, |ui| {
+						TextEdit::singleline(&mut source.proxy)
+							.hint_text("proxy (http://, https://, socks5://)")
+							.show(ui);
+						TextEdit::singleline(&mut source.ca_bundle)
+							.hint_text("CA bundle (PEM file path)")
+							.show(ui);
+						TextEdit::singleline(&mut source.client_cert)
+							.hint_text("client certificate (PEM file path)")
+							.show(ui);
+						TextEdit::singleline(&mut source.client_key)
+							.hint_text("client key (PKCS#8 PEM file path)")
+							.show(ui);
+						ui.horizontal(|ui| {
+							ui.label("rate limit");
+							ui.add(DragValue::new(&mut source.rate_limit).clamp_range(0..=6000).suffix(" req/min"))
+								.on_hover_text("requests per minute to this source host, 0 uses worker default");
+						});
+						ui.checkbox(&mut source.insecure, "skip TLS verification");
+						if source.insecure {
+							ui.colored_label(Color32::RED, "⚠ certificates will not be verified");
+						}
+					});
+				if source.kind != SourceKind::Uptime {
+					CollapsingHeader::new("request")
+						.id_source(format!("source-request-{}", source.id))
+						.show(ui, |ui| {
+							TextEdit::multiline(&mut source.headers)
+								.hint_text("headers, one per line (X-Job: {{jobs:.\"current\"}})")
+								.code_editor()
+								.desired_rows(2)
+								.show(ui);
+							TextEdit::multiline(&mut source.body)
+								.hint_text("body, sent with POST when not empty")
+								.code_editor()
+								.desired_rows(2)
+								.show(ui);
+							ui.small("{{source:query}} is replaced with the value query finds in that source latest payload")
+								.on_hover_text("values are url encoded in urls, and must be numbers in sql queries");
+						});
+					CollapsingHeader::new("oauth2")
+						.id_source(format!("source-oauth-{}", source.id))
+						.show(ui, |ui| {
+							TextEdit::singleline(&mut source.token_url)
+								.hint_text("token url (empty to disable)")
+								.show(ui);
+							TextEdit::singleline(&mut source.client_id)
+								.hint_text("client id")
+								.show(ui);
+							let literal = !source.client_secret.starts_with("env:") && !source.client_secret.starts_with("file:");
+							TextEdit::singleline(&mut source.client_secret)
+								.hint_text("client secret (env:VAR or file:/path)")
+								.password(literal)
+								.show(ui);
+							if literal && !source.client_secret.is_empty() {
+								ui.colored_label(Color32::YELLOW, "⚠ stored in plain text in database")
+									.on_hover_text("use env:VAR or file:/path to keep the secret out of the database");
+							}
+							TextEdit::singleline(&mut source.scope)
+								.hint_text("scope")
+								.show(ui);
+						});
+				}
+			}
 		},
 		EditingModelType::EditingMetric { metric } => {
 			ui.horizontal(|ui| {
@@ -282,19 +532,155 @@ pub fn popup_edit_ui(
 			TextEdit::singleline(&mut metric.query)
 				.hint_text("query")
 				.show(ui);
+			ui.horizontal(|ui| {
+				for (label, bound) in [("min", &mut metric.valid_min), ("max", &mut metric.valid_max)] {
+					let mut set = bound.is_some();
+					ui.checkbox(&mut set, label);
+					match (set, bound.as_mut()) {
+						(true, Some(v)) => { ui.add(DragValue::new(v).speed(0.1)); },
+						(true, None) => *bound = Some(0.0),
+						(false, _) => *bound = None,
+					}
+				}
+				if metric.range_inverted() {
+					ui.colored_label(Color32::RED, "⚠ min is above max");
+				} else if metric.valid_min.is_some() || metric.valid_max.is_some() {
+					ComboBox::from_id_source(format!("metric-out-of-range-{}", metric.id))
+						.selected_text(metric.out_of_range.label())
+						.show_ui(ui, |ui| {
+							for policy in OutOfRange::iter() {
+								ui.selectable_value(&mut metric.out_of_range, policy, policy.label());
+							}
+						})
+						.response
+						.on_hover_text("what to do with values out of range: drop them, clamp them to bounds or store them flagged, hidden from plots");
+				}
+			});
+			ui.horizontal(|ui| {
+				ui.checkbox(&mut metric.on_change, "on change")
+					.on_hover_text("only store values which moved past deadband since last stored one");
+				if metric.on_change {
+					ui.add(DragValue::new(&mut metric.deadband).speed(0.1).clamp_range(0.0..=f64::MAX));
+					ui.toggle_value(&mut metric.deadband_percent, "%");
+					ui.add(DragValue::new(&mut metric.heartbeat).clamp_range(0..=10080).prefix("heartbeat ").suffix(" min"))
+						.on_hover_text("store a point anyway after this long, 0 disables");
+				}
+			});
+			ui.horizontal(|ui| {
+				let mut own = metric.retention.is_some();
+				ui.checkbox(&mut own, "retention")
+					.on_hover_text("days of points to keep, otherwise worker default applies");
+				match (own, metric.retention.as_mut()) {
+					(true, Some(days)) => { ui.add(DragValue::new(days).clamp_range(0..=36500).suffix(" days")); },
+					(true, None) => metric.retention = Some(0),
+					(false, _) => metric.retention = None,
+				}
+				if metric.retention == Some(0) {
+					ui.small("kept forever");
+				}
+			});
+			if !model.new {
+				ui.horizontal(|ui| {
+					TextEdit::singleline(&mut model.csv_path)
+						.hint_text("csv file")
+						.desired_width(ui.available_width() - 100.0)
+						.show(ui);
+					let path = model.csv_path.trim();
+					if ui.add_enabled(!path.is_empty(), Button::new("export").small())
+						.on_hover_text("write all points of this metric to csv file").clicked() {
+						model.transfer = Some(BackgroundAction::ExportCsv { metric: metric.clone(), path: path.into() });
+					}
+					if ui.add_enabled(!path.is_empty(), Button::new("import").small())
+						.on_hover_text("add points from csv file to this metric, as exported").clicked() {
+						model.transfer = Some(BackgroundAction::ImportCsv { metric: metric.clone(), path: path.into() });
+					}
+				});
+			}
+			match payloads.get(&metric.source_id) {
+				Some(payload) => {
+					match metric.extract(payload) {
+						Ok(Some(v)) => ui.label(format!("= {}", v)),
+						Ok(None) => ui.colored_label(Color32::YELLOW, "not a number"),
+						Err(e) => ui.colored_label(Color32::RED, format!("{:?}", e)),
+					};
+					let mut pick = None;
+					CollapsingState::load_with_default_open(
+						ui.ctx(),
+						ui.make_persistent_id(format!("metric-{}-payload", metric.id)),
+						false,
+					)
+					.show_header(ui, |ui| {
+						ui.label("payload");
+						if ui.small_button("refresh").clicked() {
+							model.payload_requested = None;
+						}
+					})
+					.body(|ui| {
+						ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+							match payload {
+								Payload::Json(value) => payload_tree_ui(ui, value, "", &mut pick),
+								Payload::Html(doc) => { ui.monospace(&doc.source); },
+							}
+						});
+					});
+					if let Some(query) = pick {
+						metric.query = query;
+					}
+				},
+				None => { ui.label("no payload fetched for this source"); },
+			}
+		},
+		EditingModelType::DiscoverSource { source, fields } => {
+			ui.label(format!("numeric fields found in '{}'", source.name));
+			if fields.is_empty() {
+				match payloads.get(&source.id) {
+					Some(Payload::Json(value)) => *fields = numeric_leaves(value).into_iter().map(|l| (l, false)).collect(),
+					Some(Payload::Html(_)) => { ui.label("fields can't be discovered in html pages, add metrics with css selectors"); },
+					None => { ui.label("fetching..."); },
+				}
+			}
+			ui.horizontal(|ui| {
+				if ui.small_button("all").clicked() {
+					fields.iter_mut().for_each(|(_, selected)| *selected = true);
+				}
+				if ui.small_button("none").clicked() {
+					fields.iter_mut().for_each(|(_, selected)| *selected = false);
+				}
+			});
+			ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+				for (leaf, selected) in fields.iter_mut() {
+					ui.horizontal(|ui| {
+						ui.checkbox(selected, "");
+						TextEdit::singleline(&mut leaf.name)
+							.hint_text("name")
+							.desired_width(120.0)
+							.show(ui);
+						ui.label(format!("= {}", leaf.value)).on_hover_text(&leaf.query);
+					});
+				}
+			});
 		},
 	}
 	ui.separator();
 	ui.horizontal(|ui| {
-		if ui.button("   save   ").clicked() {
+		let savable = !matches!(&model.m, EditingModelType::EditingMetric { metric } if metric.range_inverted());
+		if ui.add_enabled(savable, Button::new("   save   ")).clicked() {
 			model.valid = true;
 			model.ready = true;
 		}
 		ui.with_layout(Layout::top_down(Align::RIGHT), |ui| {
-			if ui.button("   close   ").clicked() {
-				model.valid = false;
-				model.ready = true;
-			}
+			ui.horizontal(|ui| {
+				if ui.button("   close   ").clicked() {
+					model.valid = false;
+					model.ready = true;
+				}
+				let deletable = !model.new && !matches!(model.m, EditingModelType::DiscoverSource { .. });
+				if deletable && ui.button(" delete ").clicked() {
+					model.delete = true;
+					model.valid = false;
+					model.ready = true;
+				}
+			});
 		});
 	});
 }
@@ -1,13 +1,16 @@
-use eframe::egui::{ScrollArea, Ui, DragValue, TextEdit, Checkbox};
+use eframe::egui::{ScrollArea, Ui, DragValue, TextEdit, Checkbox, Color32};
 
 use crate::gui::App;
+use crate::worker::BackgroundAction;
 use crate::data::entities;
 
 use super::metric::metric_line_ui;
+use super::scaffold::EditingModel;
 
 pub fn source_panel_ui(app: &mut App, ui: &mut Ui) {
 	let panel_width = ui.available_width();
 	let mut orphaned_metrics = app.view.metrics.borrow().clone();
+	let mut backfills = vec![];
 	ScrollArea::vertical()
 		.max_width(panel_width)
 		.show(ui, |ui| {
@@ -24,6 +27,9 @@ pub fn source_panel_ui(app: &mut App, ui: &mut Ui) {
 							if ui.small_button("#").clicked() {
 								app.editing.push(source.clone().into());
 							}
+							if ui.small_button("🔍").on_hover_text("discover metrics").clicked() {
+								app.editing.push(EditingModel::make_discover(source.clone()));
+							}
 						});
 						ui.vertical(|ui| { // actual sources list container
 							ui.group(|ui| {
@@ -43,6 +49,9 @@ pub fn source_panel_ui(app: &mut App, ui: &mut Ui) {
 												// TODO don't add duplicates
 												app.editing.push(metric.clone().into());
 											}
+											if source.archive && ui.small_button("⏪").on_hover_text("backfill from archived payloads").clicked() {
+												backfills.push(metric.clone());
+											}
 										});
 									}
 								}
@@ -84,6 +93,9 @@ pub fn source_panel_ui(app: &mut App, ui: &mut Ui) {
 				});
 			}
 		});
+	for metric in backfills {
+		app.op(BackgroundAction::Backfill { metric });
+	}
 }
 
 pub fn source_line_ui(ui: &mut Ui, source: &entities::sources::Model) {
@@ -92,11 +104,23 @@ pub fn source_line_ui(ui: &mut Ui, source: &entities::sources::Model) {
 	let mut enabled = source.enabled.clone();
 	ui.horizontal(|ui| {
 		ui.add_enabled(false, Checkbox::new(&mut enabled, ""));
+		let mut badge = if source.insecure { 18.0 } else { 0.0 };
+		if source.rejected > 0 {
+			badge += 18.0;
+		}
 		TextEdit::singleline(&mut name)
-			.desired_width(ui.available_width() - 58.0)
+			.desired_width(ui.available_width() - 58.0 - badge)
 			.interactive(false)
 			.hint_text("name")
 			.show(ui);
+		if source.insecure {
+			ui.colored_label(Color32::YELLOW, "⚠")
+				.on_hover_text("TLS verification disabled");
+		}
+		if source.rejected > 0 {
+			ui.colored_label(Color32::LIGHT_RED, "⛔")
+				.on_hover_text(format!("{} values rejected at ingest", source.rejected));
+		}
 		ui.add_enabled(false, DragValue::new(&mut interval).clamp_range(1..=3600));
 	});
 }
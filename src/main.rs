@@ -3,20 +3,29 @@ mod data;
 mod util;
 mod worker;
 
-use std::sync::Arc;
+use std::{sync::Arc, path::PathBuf, net::SocketAddr};
 
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::prelude::*;
-use tracing::{info, error};
+use tracing::{info, warn, error};
 use tracing_subscriber::filter::filter_fn;
 
 use eframe::egui::Context;
-use clap::{Parser, Subcommand};
-use tokio::sync::{watch, mpsc};
-use sea_orm::Database;
+use clap::{Parser, Subcommand, ValueEnum};
+use tokio::sync::{watch, mpsc, Mutex};
+use sea_orm::{Database, EntityTrait};
+use migration::{Migrator, MigratorTrait};
 
 use worker::visualizer::AppState;
-use worker::surveyor_loop;
+use worker::{surveyor_loop, SurveyorConfig};
+use worker::spool::Spool;
+use worker::telemetry::{Telemetry, telemetry_server};
+use worker::fetcher::Fetcher;
+use worker::probe::{probe, ProbeTarget};
+use worker::pruner::{self, pruner_loop};
+use worker::rollup::{self, Stage};
+use worker::transfer::{self, Columns, TransferError};
+use data::entities;
 use util::{InternalLogger, InternalLoggerLayer};
 use gui::{
 	// util::InternalLogger,
@@ -46,24 +55,196 @@ struct CliArgs {
 
 	#[arg(long)]
 	log_file: Option<String>,
+
+	/// Never migrate database schema, refuse to use outdated databases instead
+	#[arg(long)]
+	read_only: bool,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum MigrateAction {
+	/// Apply pending migrations
+	Up {
+		/// Connection string for database to migrate
+		#[arg(long)]
+		db: String,
+
+		/// Only apply this many migrations
+		#[arg(long)]
+		steps: Option<u32>,
+	},
+	/// Roll back applied migrations
+	Down {
+		/// Connection string for database to migrate
+		#[arg(long)]
+		db: String,
+
+		/// How many migrations to roll back
+		#[arg(long, default_value_t = 1)]
+		steps: u32,
+	},
+	/// List migrations and whether they're applied
+	Status {
+		/// Connection string for database to check
+		#[arg(long)]
+		db: String,
+	},
 }
 
 #[derive(Subcommand, Clone, Debug)]
 enum Mode {
 	/// Run as background service fetching sources from db
 	Worker {
-		/// Connection string for database to use
-		#[arg(required = true)]
+		/// Connection strings for databases to use, defaults to a SQLite file in user data dir
 		db_uris: Vec<String>,
+
+		/// How many points to keep on disk while database is unreachable
+		#[arg(long, default_value_t = 10000)]
+		spool_size: usize,
+
+		/// Where to keep spool files, defaults to user data dir
+		#[arg(long)]
+		spool_dir: Option<PathBuf>,
+
+		/// Serve Prometheus metrics (/metrics) and health checks (/healthz, /readyz) on this address (e.g. 127.0.0.1:9100)
+		#[arg(long)]
+		http_addr: Option<SocketAddr>,
+
+		/// Seconds to wait for in-flight fetches when stopping
+		#[arg(long, default_value_t = 10)]
+		grace_period: u64,
+
+		/// Maximum concurrent fetches across all databases, 0 for unlimited
+		#[arg(long, default_value_t = 16)]
+		max_inflight: usize,
+
+		/// Default requests per minute to each host, sources may override it. 0 for unlimited
+		#[arg(long, default_value_t = 0)]
+		host_rate: u32,
+
+		/// Days of points to keep for metrics without their own retention, 0 keeps everything
+		#[arg(long, default_value_t = 0)]
+		retention: i32,
+
+		/// Roll points older than this many days into per-minute aggregates, 0 disables
+		#[arg(long, default_value_t = 0)]
+		rollup_minute: i64,
+
+		/// Roll data older than this many days into per-hour aggregates, 0 disables
+		#[arg(long, default_value_t = 0)]
+		rollup_hour: i64,
+
+		/// Roll data older than this many days into per-day aggregates, 0 disables
+		#[arg(long, default_value_t = 0)]
+		rollup_day: i64,
+	},
+	/// Delete points older than each metric retention
+	Prune {
+		/// Connection string for database to prune, defaults to a SQLite file in user data dir
+		#[arg(long)]
+		db: Option<String>,
+
+		/// Days of points to keep for metrics without their own retention, 0 keeps everything
+		#[arg(long, default_value_t = 0)]
+		retention: i32,
+
+		/// Only report how many points each metric would lose
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// Fetch a source once and show what its metrics would extract, without storing anything
+	Probe {
+		/// Load source and its metrics from this database
+		#[arg(long, requires = "source", conflicts_with = "url")]
+		db: Option<String>,
+
+		/// Id or name of source to probe
+		#[arg(long)]
+		source: Option<String>,
+
+		/// Probe this url instead of a stored source
+		#[arg(long, required_unless_present = "db")]
+		url: Option<String>,
+
+		/// Queries to try on fetched payload, only with --url
+		#[arg(long, requires = "url")]
+		query: Vec<String>,
+	},
+	/// Write points of a metric to a file
+	Export {
+		/// Connection string for database to read from, defaults to a SQLite file in user data dir
+		#[arg(long)]
+		db: Option<String>,
+
+		/// Id of metric to export
+		#[arg(long)]
+		metric: i64,
+
+		/// Only export points after this unix timestamp
+		#[arg(long)]
+		from: Option<f64>,
+
+		/// Only export points before this unix timestamp
+		#[arg(long)]
+		to: Option<f64>,
+
+		#[arg(long, value_enum, default_value_t = Format::Csv)]
+		format: Format,
+
+		/// File to write
+		path: PathBuf,
+	},
+	/// Add points from a file to a metric
+	Import {
+		/// Connection string for database to write to, defaults to a SQLite file in user data dir
+		#[arg(long)]
+		db: Option<String>,
+
+		/// Id of metric receiving points
+		#[arg(long)]
+		metric: i64,
+
+		#[arg(long, value_enum, default_value_t = Format::Csv)]
+		format: Format,
+
+		/// Column holding point timestamps, counting from 0
+		#[arg(long, default_value_t = 1)]
+		x_column: usize,
+
+		/// Column holding point values, counting from 0
+		#[arg(long, default_value_t = 2)]
+		y_column: usize,
+
+		/// Only report how many points would be stored
+		#[arg(long)]
+		dry_run: bool,
+
+		/// File to read, first line is always skipped as header
+		path: PathBuf,
+	},
+	/// Manage database schema
+	Migrate {
+		#[command(subcommand)]
+		action: MigrateAction,
 	},
 	/// Run as foreground user interface displaying collected data
 	GUI {
-		/// Immediately connect to this database on startup
+		/// Database to connect to on startup, defaults to a SQLite file in user data dir
 		#[arg(short, long)]
 		db_uri: Option<String>,
 	},
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+	Csv,
+}
+
+enum Stop {
+	Signal,
+	Worker,
+}
+
 fn setup_tracing(layer: Option<InternalLoggerLayer>, log_to_file:Option<String>) {
 	let file_layer = if let Some(path) = log_to_file {
 		let file = std::fs::File::create(path).expect("Cannot open requested log file for writing");
@@ -90,36 +271,96 @@ fn main() {
 	let (run_tx, run_rx) = watch::channel(true);
 
 	match args.mode {
-		Mode::Worker { db_uris } => {
+		Mode::Worker { db_uris, spool_size, spool_dir, http_addr, grace_period, max_inflight, host_rate, retention, rollup_minute, rollup_hour, rollup_day } => {
 			setup_tracing(None, args.log_file);
+			let db_uris = if db_uris.is_empty() { vec![data::default_uri()] } else { db_uris };
+			let stages = vec![
+				Stage { resolution: rollup::MINUTE, after: rollup_minute },
+				Stage { resolution: rollup::HOUR, after: rollup_hour },
+				Stage { resolution: rollup::DAY, after: rollup_day },
+			];
+			if let Err(e) = rollup::check_stages(&stages) {
+				error!(target: "launcher", "Invalid rollup settings: {}", e);
+				std::process::exit(1);
+			}
+
+			let (stop_tx, stop_rx) = std::sync::mpsc::channel(); // TODO can I avoid using a std channel?
+			let worker_stop_tx = stop_tx.clone();
 
 			let worker = std::thread::spawn(move || {
-				tokio::runtime::Builder::new_multi_thread()
+				let res = tokio::runtime::Builder::new_multi_thread()
 					.enable_all()
 					.build()
 					.unwrap()
 					.block_on(async {
 						let mut jobs = vec![];
+						let telemetry = Arc::new(Telemetry::default());
+						let fetcher = Fetcher::limited(max_inflight, host_rate);
+						let config = SurveyorConfig {
+							interval: args.interval as i64,
+							cache_time: args.cache_time as i64,
+							grace_period: std::time::Duration::from_secs(grace_period),
+						};
+
+						if let Some(addr) = http_addr {
+							let listener = match std::net::TcpListener::bind(addr) {
+								Ok(l) => l,
+								Err(e) => {
+									error!(target: "worker", "Could not bind telemetry server to {}: {:?}", addr, e);
+									return Err(());
+								}
+							};
+							// consider a surveyor stuck if it skips a few activations
+							let timeout = std::cmp::max(3 * args.interval as i64, 30);
+							jobs.push(
+								tokio::spawn(telemetry_server(listener, telemetry.clone(), db_uris.len(), timeout, run_rx.clone()))
+							);
+						}
 
 						for (i, db_uri) in db_uris.iter().enumerate() {
-							let db = match Database::connect(db_uri.clone()).await {
+							let db = match data::connect(db_uri, args.read_only).await {
 								Ok(v) => v,
 								Err(e) => {
 									error!(target: "worker", "Could not connect to db #{}: {:?}", i, e);
-									return;
+									return Err(());
 								}
 							};
 
 							info!(target: "worker", "Connected to #{}: '{}'", i, db_uri);
+							telemetry.db_state(i, true);
+
+							let dir = spool_dir.clone().unwrap_or_else(Spool::default_dir);
+							let spool_path = dir.join(Spool::file_name(db_uri));
+							// files were once named after db position, can't tell which db they belong to
+							let legacy = dir.join(format!("spool-{}.jsonl", i));
+							if legacy.exists() {
+								warn!(target: "worker", "Ignoring old spool file '{}', replay it by hand into the right database", legacy.display());
+							}
+							let spool = match Spool::open(spool_path.clone(), spool_size) {
+								Ok(s) => s,
+								Err(e) => {
+									error!(target: "worker", "Could not open spool '{}' for db #{}: {:?}", spool_path.display(), i, e);
+									return Err(());
+								}
+							};
+							if !spool.is_empty() {
+								info!(target: "worker", "Spool for db #{} holds {} points, will replay", i, spool.depth());
+							}
+
+							jobs.push(
+								tokio::spawn(pruner_loop(db.clone(), retention, stages.clone(), run_rx.clone(), i))
+							);
 
 							jobs.push(
 								tokio::spawn(
 									surveyor_loop(
 										db,
-										args.interval as i64,
-										args.cache_time as i64,
+										config.clone(),
 										run_rx.clone(),
 										i,
+										Arc::new(Mutex::new(spool)),
+										telemetry.clone(),
+										Arc::new(fetcher.sibling()), // source ids of different dbs may clash
 									)
 								)
 							);
@@ -132,19 +373,162 @@ fn main() {
 						}
 
 						info!(target: "worker", "Stopping background worker");
-					})
+						Ok(())
+					});
+				worker_stop_tx.send(Stop::Worker).unwrap_or(()); // main thread may already be stopping
+				res
 			});
 
-			let (sigint_tx, sigint_rx) = std::sync::mpsc::channel(); // TODO can I avoid using a std channel?
 			ctrlc::set_handler(move ||
-				sigint_tx.send(()).expect("Could not send signal on channel")
-			).expect("Could not set SIGINT handler");
+				stop_tx.send(Stop::Signal).expect("Could not send signal on channel")
+			).expect("Could not set SIGINT/SIGTERM handler");
 
-			sigint_rx.recv().expect("Could not receive signal from channel");
-			info!(target: "launcher", "Received SIGINT, stopping...");
+			let unexpected = match stop_rx.recv().expect("Could not receive signal from channel") {
+				Stop::Signal => { info!(target: "launcher", "Received SIGINT/SIGTERM, stopping..."); false },
+				Stop::Worker => { error!(target: "launcher", "Background worker stopped unexpectedly"); true },
+			};
 
 			run_tx.send(false).unwrap_or(()); // ignore errors
-			worker.join().expect("Failed joining worker thread");
+			if worker.join().expect("Failed joining worker thread").is_err() || unexpected {
+				std::process::exit(1);
+			}
+		},
+
+		Mode::Probe { db, source, url, query } => {
+			setup_tracing(None, args.log_file);
+
+			let target = match (db, source, url) {
+				(Some(db_uri), Some(source), _) => ProbeTarget::Stored { db_uri, source },
+				(_, _, Some(url)) => ProbeTarget::Adhoc { url, queries: query },
+				_ => unreachable!("clap enforces either --db and --source or --url"),
+			};
+
+			let res = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.unwrap()
+				.block_on(probe(target));
+
+			if let Err(e) = res {
+				error!(target: "probe", "Probe failed: {:?}", e);
+				std::process::exit(1);
+			}
+		},
+
+		Mode::Prune { db, retention, dry_run } => {
+			setup_tracing(None, args.log_file);
+
+			let res = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.unwrap()
+				.block_on(async {
+					let db = data::connect(&db.unwrap_or_else(data::default_uri), args.read_only).await?;
+					if dry_run {
+						for (metric, days, expired) in pruner::report(&db, retention).await? {
+							match days {
+								0 => println!("  {} [kept forever]", metric.name),
+								_ => println!("  {} [{} days] would remove {} points", metric.name, days, expired),
+							}
+						}
+					} else {
+						let removed = pruner::prune(&db, retention, &run_rx).await?;
+						info!(target: "pruner", "Removed {} expired points", removed);
+					}
+					Ok::<(), sea_orm::DbErr>(())
+				});
+
+			if let Err(e) = res {
+				error!(target: "pruner", "Prune failed: {:?}", e);
+				std::process::exit(1);
+			}
+		},
+
+		Mode::Export { db, metric, from, to, format: Format::Csv, path } => {
+			setup_tracing(None, args.log_file);
+
+			let res = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.unwrap()
+				.block_on(async {
+					let db = data::connect(&db.unwrap_or_else(data::default_uri), args.read_only).await?;
+					let metric = entities::metrics::Entity::find_by_id(metric).one(&db).await?
+						.ok_or_else(|| format!("no metric with id {}", metric))?;
+					let count = transfer::export(&db, &metric, from, to, path.clone()).await?;
+					info!(target: "transfer", "Exported {} points of '{}' to {}", count, metric.name, path.display());
+					Ok::<(), TransferError>(())
+				});
+
+			if let Err(e) = res {
+				error!(target: "transfer", "Export failed: {}", e);
+				std::process::exit(1);
+			}
+		},
+
+		Mode::Import { db, metric, format: Format::Csv, x_column, y_column, dry_run, path } => {
+			setup_tracing(None, args.log_file);
+
+			let res = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.unwrap()
+				.block_on(async {
+					let db = data::connect(&db.unwrap_or_else(data::default_uri), args.read_only).await?;
+					let metric = entities::metrics::Entity::find_by_id(metric).one(&db).await?
+						.ok_or_else(|| format!("no metric with id {}", metric))?;
+					let columns = Columns { x: x_column, y: y_column };
+					let report = transfer::import(&db, &metric, path.clone(), columns, dry_run).await?;
+					if dry_run {
+						println!(
+							"  {} records read, would store {} points into '{}' ({} flagged), {} already stored, {} rejected",
+							report.read, report.inserted, metric.name, report.flagged, report.skipped, report.rejected,
+						);
+						for e in report.malformed.iter() {
+							println!("    malformed {}", e);
+						}
+					} else {
+						info!(
+							target: "transfer", "Imported {} points into '{}' from {} ({} flagged, {} already stored, {} rejected)",
+							report.inserted, metric.name, path.display(), report.flagged, report.skipped, report.rejected,
+						);
+						for e in report.malformed.iter() {
+							warn!(target: "transfer", "Skipped malformed {}", e);
+						}
+					}
+					Ok::<(), TransferError>(())
+				});
+
+			if let Err(e) = res {
+				error!(target: "transfer", "Import failed: {}", e);
+				std::process::exit(1);
+			}
+		},
+
+		Mode::Migrate { action } => {
+			setup_tracing(None, args.log_file);
+
+			let res = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.unwrap()
+				.block_on(async {
+					match action {
+						MigrateAction::Up { db, steps } => Migrator::up(&Database::connect(db).await?, steps).await,
+						MigrateAction::Down { db, steps } => Migrator::down(&Database::connect(db).await?, Some(steps)).await,
+						MigrateAction::Status { db } => {
+							for (name, applied) in data::migration_status(&Database::connect(db).await?).await? {
+								println!("  {} {}", if applied { "applied" } else { "pending" }, name);
+							}
+							Ok(())
+						},
+					}
+				});
+
+			if let Err(e) = res {
+				error!(target: "migrate", "Migration failed: {:?}", e);
+				std::process::exit(1);
+			}
 		},
 
 		Mode::GUI { db_uri } => {
@@ -155,12 +539,14 @@ fn main() {
 			let logger_view = logger.view();
 
 			setup_tracing(Some(logger.layer()), args.log_file);
+			let db_uri = db_uri.unwrap_or_else(data::default_uri);
 
 			let state = match AppState::new(
 				width_rx,
 				uri_rx,
 				args.interval as i64,
 				args.cache_time as i64,
+				args.read_only,
 			) {
 				Ok(s) => s,
 				Err(e) => {
@@ -235,7 +621,7 @@ fn main() {
 						Box::new(
 							App::new(
 								cc,
-								db_uri,
+								Some(db_uri),
 								uri_tx,
 								args.interval as i64,
 								view,
@@ -10,7 +10,8 @@ use super::data::entities;
 // if you're handling more than terabytes of data, it's the future and you ought to update this code!
 const _PREFIXES: &'static [&'static str] = &["", "k", "M", "G", "T"];
 
-pub fn _serialize_values(values: &Vec<PlotPoint>, metric: &entities::metrics::Model, path: PathBuf) -> Result<(), Box<dyn Error>> {
+/// Write values as CSV: a header with metric name and query, then one `,x,y` record per value
+pub fn serialize_values(values: &[PlotPoint], metric: &entities::metrics::Model, path: PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
 	let mut wtr = csv::Writer::from_writer(std::fs::File::create(path)?);
 	// DAMN!   VVVVV
 	let name = metric.name.as_str();
@@ -24,23 +25,33 @@ pub fn _serialize_values(values: &Vec<PlotPoint>, metric: &entities::metrics::Mo
 	Ok(())
 }
 
-pub fn _deserialize_values(path: PathBuf) -> Result<(String, String, String, Vec<PlotPoint>), Box<dyn Error>> {
+/// Read values from CSV, taking x and y from given columns. First record is always a header,
+/// its first three fields are returned as they are written by `serialize_values`. Each record
+/// gives a point or, if it's malformed, why it couldn't be read.
+pub fn deserialize_values(path: PathBuf, x_col: usize, y_col: usize) -> Result<(String, String, String, Vec<Result<PlotPoint, String>>), Box<dyn Error + Send + Sync>> {
 	let mut values = Vec::new();
 
-	let mut rdr = csv::Reader::from_reader(std::fs::File::open(path)?);
+	// short records are reported as missing columns rather than failing the whole file
+	let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(std::fs::File::open(path)?);
 	let mut name = "N/A".to_string();
 	let mut query_x = "".to_string();
 	let mut query_y = "".to_string();
 	if rdr.has_headers() {
 		let record = rdr.headers()?;
-		name = record[0].to_string();
-		query_x = record[1].to_string();
-		query_y = record[2].to_string();
+		name = record.get(0).unwrap_or_default().to_string();
+		query_x = record.get(1).unwrap_or_default().to_string();
+		query_y = record.get(2).unwrap_or_default().to_string();
 	}
 	for result in rdr.records() {
-		if let Ok(record) = result {
-			values.push(PlotPoint { x: record[1].parse::<f64>()?, y: record[2].parse::<f64>()? });
-		}
+		let point = result.map_err(|e| e.to_string()).and_then(|record| {
+			let line = record.position().map(|p| p.line()).unwrap_or_default();
+			let field = |col: usize| match record.get(col) {
+				Some(v) => v.trim().parse::<f64>().map_err(|e| format!("line {}: column {} '{}': {}", line, col, v, e)),
+				None => Err(format!("line {}: no column {}", line, col)),
+			};
+			Ok(PlotPoint { x: field(x_col)?, y: field(y_col)? })
+		});
+		values.push(point);
 	}
 
 	Ok((
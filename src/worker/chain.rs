@@ -0,0 +1,236 @@
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::data::{entities::{self, sources::SourceKind}, FetchError, Payload};
+
+use super::fetcher::Fetcher;
+
+/// A `{{source:query}}` placeholder, where source is a name or an id
+struct Reference<'a> {
+	placeholder: &'a str,
+	source: &'a str,
+	query: &'a str,
+}
+
+fn references(text: &str) -> Vec<Reference<'_>> {
+	let mut found = vec![];
+	let mut rest = text;
+	while let Some(start) = rest.find("{{") {
+		let Some(len) = rest[start..].find("}}") else { break };
+		let placeholder = &rest[start..start + len + 2];
+		if let Some((source, query)) = placeholder[2..placeholder.len() - 2].split_once(':') {
+			found.push(Reference { placeholder, source: source.trim(), query: query.trim() });
+		}
+		rest = &rest[start + len + 2..];
+	}
+	found
+}
+
+fn templated(source: &entities::sources::Model) -> [&str; 3] {
+	[&source.url, &source.headers, &source.body]
+}
+
+fn find<'a>(name: &str, sources: &'a [entities::sources::Model]) -> Result<&'a entities::sources::Model, FetchError> {
+	sources.iter()
+		.find(|s| s.name == name || name.parse::<i64>().map(|id| id == s.id).unwrap_or(false))
+		.ok_or_else(|| FetchError::ChainError(format!("no source named '{}'", name)))
+}
+
+/// Sources this source takes values from
+pub fn dependencies<'a>(source: &entities::sources::Model, sources: &'a [entities::sources::Model]) -> Result<Vec<&'a entities::sources::Model>, FetchError> {
+	let mut deps : Vec<&entities::sources::Model> = vec![];
+	for text in templated(source) {
+		for reference in references(text) {
+			let dep = find(reference.source, sources)?;
+			if !deps.iter().any(|d| d.id == dep.id) {
+				deps.push(dep);
+			}
+		}
+	}
+	Ok(deps)
+}
+
+/// Fail if following this source dependencies leads back to a source already visited
+pub fn check_cycles(source: &entities::sources::Model, sources: &[entities::sources::Model]) -> Result<(), FetchError> {
+	fn visit<'a>(source: &'a entities::sources::Model, sources: &'a [entities::sources::Model], path: &mut Vec<&'a entities::sources::Model>) -> Result<(), FetchError> {
+		if path.iter().any(|s| s.id == source.id) {
+			let names : Vec<&str> = path.iter().chain(std::iter::once(&source)).map(|s| s.name.as_str()).collect();
+			return Err(FetchError::ChainError(format!("cyclic chain: {}", names.join(" -> "))));
+		}
+		path.push(source);
+		for dep in dependencies(source, sources)? {
+			visit(dep, sources, path)?;
+		}
+		path.pop();
+		Ok(())
+	}
+	visit(source, sources, &mut vec![])
+}
+
+/// Percent-encode everything but unreserved characters, so values can't alter url structure
+fn url_encode(value: &str) -> String {
+	let mut out = String::with_capacity(value.len());
+	for b in value.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+			_ => out.push_str(&format!("%{:02X}", b)),
+		}
+	}
+	out
+}
+
+/// Value found in a dependency, made safe for the field it goes into: url encoded in urls, single
+/// line in headers. Sql queries only accept numbers, anything else could change the query.
+fn escape(value: &str, field: Field, kind: SourceKind, dep: &str) -> Result<String, FetchError> {
+	match field {
+		Field::Url => Ok(url_encode(value)),
+		Field::Headers if value.contains(['\r', '\n']) =>
+			Err(FetchError::ChainError(format!("value from {} spans multiple lines, can't be used in headers", dep))),
+		Field::Body if kind == SourceKind::Sql => match value.trim().parse::<f64>() {
+			Ok(n) if n.is_finite() => Ok(value.trim().to_string()),
+			_ => Err(FetchError::ChainError(format!("value '{}' from {} is not a number, can't be used in a query", value, dep))),
+		},
+		_ => Ok(value.to_string()),
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+	Url,
+	Headers,
+	Body,
+}
+
+/// Copy of source with placeholders replaced by values extracted from dependencies payloads
+fn substitute(
+	source: &entities::sources::Model,
+	sources: &[entities::sources::Model],
+	payloads: &[(&entities::sources::Model, Payload)],
+) -> Result<entities::sources::Model, FetchError> {
+	let mut resolved = source.clone();
+	let fields = [(Field::Url, &mut resolved.url), (Field::Headers, &mut resolved.headers), (Field::Body, &mut resolved.body)];
+	for (kind, field) in fields {
+		let mut text = field.clone();
+		for reference in references(field) {
+			let (dep, payload) = payloads.iter()
+				.find(|(d, _)| find(reference.source, sources).map(|s| s.id == d.id).unwrap_or(false))
+				.ok_or_else(|| FetchError::ChainError(format!("no source named '{}'", reference.source)))?;
+			let value = payload.lookup(reference.query)?
+				.ok_or_else(|| FetchError::ChainError(format!("'{}' found nothing in {}", reference.query, dep.name)))?;
+			text = text.replacen(reference.placeholder, &escape(&value, kind, source.kind, &dep.name)?, 1);
+		}
+		*field = text;
+	}
+	Ok(resolved)
+}
+
+/// Copy of source with placeholders in url, headers and body replaced by values extracted from
+/// dependencies. Dependencies with a payload older than their interval are polled first, waiting
+/// their turn like any other poll; dependencies backing off make resolving fail.
+pub fn resolve<'a>(
+	fetcher: &'a Fetcher,
+	source: &'a entities::sources::Model,
+	sources: &'a [entities::sources::Model],
+) -> BoxFuture<'a, Result<entities::sources::Model, FetchError>> {
+	async move {
+		let deps = dependencies(source, sources)?;
+		if deps.is_empty() {
+			return Ok(source.clone());
+		}
+		check_cycles(source, sources)?;
+
+		let mut payloads = vec![];
+		for dep in deps {
+			let payload = match fetcher.latest(dep.id, dep.interval as i64) {
+				Some(p) => p,
+				None => {
+					if let Some(wait) = fetcher.backoff(dep.id) {
+						return Err(FetchError::ChainError(format!("dependency {} is backing off for {}s", dep.name, wait)));
+					}
+					fetcher.poll(&resolve(fetcher, dep, sources).await?).await?.payload
+				},
+			};
+			payloads.push((dep, payload));
+		}
+		substitute(source, sources, &payloads)
+	}.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn source(id: i64, name: &str, url: &str) -> entities::sources::Model {
+		entities::sources::Model { id, name: name.into(), url: url.into(), ..Default::default() }
+	}
+
+	#[test]
+	fn references_are_parsed() {
+		let found = references("http://x/{{auth : \"token\"}}/{{2:\"id\"}}?q={{broken}}&r={{unterminated");
+		let parsed : Vec<(&str, &str, &str)> = found.iter().map(|r| (r.placeholder, r.source, r.query)).collect();
+		assert_eq!(parsed, vec![
+			("{{auth : \"token\"}}", "auth", "\"token\""),
+			("{{2:\"id\"}}", "2", "\"id\""),
+		]);
+		assert!(references("no placeholders").is_empty());
+	}
+
+	#[test]
+	fn dependencies_by_name_or_id() {
+		let sources = vec![
+			source(1, "auth", ""),
+			source(2, "list", ""),
+			source(3, "item", "http://x/{{auth:\"t\"}}/{{2:\"id\"}}/{{auth:\"u\"}}"),
+		];
+		let deps : Vec<i64> = dependencies(&sources[2], &sources).unwrap().iter().map(|s| s.id).collect();
+		assert_eq!(deps, vec![1, 2]);
+		let orphan = source(4, "orphan", "{{missing:\"x\"}}");
+		assert!(matches!(dependencies(&orphan, &sources), Err(FetchError::ChainError(_))));
+	}
+
+	#[test]
+	fn cycles_are_found() {
+		let sources = vec![
+			source(1, "a", "{{b:\"x\"}}"),
+			source(2, "b", "{{c:\"x\"}}"),
+			source(3, "c", "{{a:\"x\"}}"),
+			source(4, "d", "{{e:\"x\"}}{{f:\"x\"}}"),
+			source(5, "e", "{{f:\"x\"}}"),
+			source(6, "f", ""),
+			source(7, "self", "{{self:\"x\"}}"),
+		];
+		match check_cycles(&sources[0], &sources) {
+			Err(FetchError::ChainError(msg)) => assert_eq!(msg, "cyclic chain: a -> b -> c -> a"),
+			other => panic!("expected a cycle, got {:?}", other),
+		}
+		assert!(check_cycles(&sources[3], &sources).is_ok()); // diamond, not a cycle
+		assert!(check_cycles(&sources[6], &sources).is_err());
+	}
+
+	#[test]
+	fn values_are_escaped_for_their_field() {
+		let auth = source(1, "auth", "");
+		let payload = Payload::Json(json!({ "token": "a b&c/d", "multi": "x\r\nEvil: 1", "n": 42, "sql": "1; DROP TABLE points" }));
+		let sources = vec![auth.clone()];
+		let payloads = vec![(&auth, payload)];
+
+		let mut http = source(2, "http", "http://x/items?t={{auth:\"token\"}}");
+		http.headers = "Authorization: Bearer {{auth:\"token\"}}".into();
+		http.body = "{\"n\": {{auth:\"n\"}}}".into();
+		let resolved = substitute(&http, &sources, &payloads).unwrap();
+		assert_eq!(resolved.url, "http://x/items?t=a%20b%26c%2Fd");
+		assert_eq!(resolved.headers, "Authorization: Bearer a b&c/d");
+		assert_eq!(resolved.body, "{\"n\": 42}");
+
+		http.headers = "X-Token: {{auth:\"multi\"}}".into();
+		assert!(substitute(&http, &sources, &payloads).is_err());
+
+		let mut sql = source(3, "sql", "sqlite://db");
+		sql.kind = SourceKind::Sql;
+		sql.body = "SELECT v FROM t WHERE id = {{auth:\"n\"}}".into();
+		assert_eq!(substitute(&sql, &sources, &payloads).unwrap().body, "SELECT v FROM t WHERE id = 42");
+		sql.body = "SELECT v FROM t WHERE id = {{auth:\"sql\"}}".into();
+		assert!(substitute(&sql, &sources, &payloads).is_err());
+	}
+}
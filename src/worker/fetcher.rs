@@ -0,0 +1,452 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{Client, Certificate, Identity, Proxy, RequestBuilder, Response, StatusCode, header::{self, HeaderValue}};
+use tokio::{sync::{Semaphore, OwnedSemaphorePermit}, time::Instant};
+use tracing::{debug, warn};
+
+use crate::data::{entities::{self, sources::SourceKind}, html::Document, FetchError, Payload};
+
+use super::{sql::SqlPool, uptime, oauth::TokenCache};
+
+/// Source fields affecting how connections are made: a client is rebuilt when any changes
+#[derive(Clone, PartialEq, Eq)]
+struct Transport {
+	ca_bundle: String,
+	client_cert: String,
+	client_key: String,
+	insecure: bool,
+	proxy: String,
+}
+
+impl From<&entities::sources::Model> for Transport {
+	fn from(s: &entities::sources::Model) -> Self {
+		Transport {
+			ca_bundle: s.ca_bundle.clone(),
+			client_cert: s.client_cert.clone(),
+			client_key: s.client_key.clone(),
+			insecure: s.insecure,
+			proxy: s.proxy.clone(),
+		}
+	}
+}
+
+impl Transport {
+	fn client(&self) -> Result<Client, FetchError> {
+		let mut builder = Client::builder();
+		if !self.ca_bundle.is_empty() {
+			for cert in Certificate::from_pem_bundle(&std::fs::read(&self.ca_bundle)?)? {
+				builder = builder.add_root_certificate(cert);
+			}
+		}
+		if !self.client_cert.is_empty() {
+			let cert = std::fs::read(&self.client_cert)?;
+			let key = std::fs::read(&self.client_key)?;
+			builder = builder.identity(Identity::from_pkcs8_pem(&cert, &key)?);
+		}
+		if self.insecure {
+			builder = builder.danger_accept_invalid_certs(true);
+		}
+		if !self.proxy.is_empty() {
+			builder = builder.proxy(Proxy::all(&self.proxy)?);
+		}
+		Ok(builder.build()?)
+	}
+}
+
+// longest wait after repeated 429s without a Retry-After header
+const MAX_BACKOFF : i64 = 3600;
+
+/// Validators and body of last response for a source, to make conditional requests
+struct Cached {
+	url: String,
+	etag: Option<HeaderValue>,
+	last_modified: Option<HeaderValue>,
+	payload: Payload,
+}
+
+/// Source was told to slow down: no request should be made before `until`
+struct Throttle {
+	until: i64,
+	strikes: u32,
+}
+
+/// Result of polling a source: `modified` is false if server replied 304 and cached payload is returned
+pub struct Polled {
+	pub payload: Payload,
+	pub modified: bool,
+}
+
+/// Makes requests for sources, keeping one client per source configured with its tls and proxy options.
+/// Polls can be limited in concurrency and in rate per host: requests over limits wait their turn.
+/// Source ids are only unique within a database, so each database needs its own fetcher.
+#[derive(Default)]
+pub struct Fetcher {
+	clients: Mutex<HashMap<i64, (Transport, Client)>>,
+	cache: Mutex<HashMap<i64, Cached>>,
+	throttle: Mutex<HashMap<i64, Throttle>>,
+	inflight: Option<Arc<Semaphore>>,
+	host_rate: u32,
+	next_slot: Arc<Mutex<HashMap<String, Instant>>>,
+	databases: Mutex<HashMap<i64, (String, SqlPool)>>,
+	tokens: Arc<TokenCache>,
+	latest: Mutex<HashMap<i64, (Instant, Payload)>>,
+}
+
+async fn read(kind: SourceKind, res: Response) -> Result<Payload, FetchError> {
+	match kind {
+		SourceKind::Html => Ok(Payload::Html(Document::parse(res.text().await?))),
+		_ => Ok(Payload::Json(res.json().await?)),
+	}
+}
+
+/// GET source url, or POST its body when it has one, with headers given one per line as `Name: value`
+fn request(client: &Client, source: &entities::sources::Model) -> RequestBuilder {
+	let mut req = if source.body.is_empty() {
+		client.get(&source.url)
+	} else {
+		client.post(&source.url).body(source.body.clone())
+	};
+	for line in source.headers.lines() {
+		if let Some((name, value)) = line.split_once(':') {
+			req = req.header(name.trim(), value.trim());
+		}
+	}
+	req
+}
+
+fn retry_after(res: &Response) -> Option<i64> {
+	let value = res.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+	match value.parse::<i64>() {
+		Ok(secs) => Some(secs),
+		Err(_) => DateTime::parse_from_rfc2822(value).ok()
+			.map(|t| t.timestamp() - Utc::now().timestamp()),
+	}
+}
+
+impl Fetcher {
+	/// Allow at most `max_inflight` concurrent polls and `host_rate` requests per minute to each
+	/// host (unless source sets its own). Zero means unlimited.
+	pub fn limited(max_inflight: usize, host_rate: u32) -> Self {
+		Fetcher {
+			inflight: if max_inflight > 0 { Some(Arc::new(Semaphore::new(max_inflight))) } else { None },
+			host_rate,
+			..Default::default()
+		}
+	}
+
+	/// Fetcher for another database, sharing concurrency and host rate limits and oauth tokens
+	/// with this one but keeping its own clients, caches and backoffs
+	pub fn sibling(&self) -> Self {
+		Fetcher {
+			inflight: self.inflight.clone(),
+			host_rate: self.host_rate,
+			next_slot: self.next_slot.clone(),
+			tokens: self.tokens.clone(),
+			..Default::default()
+		}
+	}
+
+	/// Wait until source host has a free slot and a concurrency permit is available. Slots are
+	/// reserved immediately, so waiting requests go out in the order they asked.
+	async fn wait_turn(&self, source: &entities::sources::Model) -> Option<OwnedSemaphorePermit> {
+		let rate = if source.rate_limit > 0 { source.rate_limit as u32 } else { self.host_rate };
+		let host = reqwest::Url::parse(&source.url).ok()
+			.and_then(|u| u.host_str().map(|h| h.to_string()));
+		if let (true, Some(host)) = (rate > 0, host) {
+			let slot = {
+				let mut next_slot = self.next_slot.lock().unwrap_or_else(|e| e.into_inner());
+				let now = Instant::now();
+				let next = next_slot.entry(host.clone()).or_insert(now);
+				let slot = std::cmp::max(*next, now);
+				*next = slot + Duration::from_secs_f64(60.0 / rate as f64);
+				slot
+			};
+			if slot > Instant::now() {
+				debug!(target: "fetcher", "Request for source '{}' queued {:.1}s for host {}", source.name, (slot - Instant::now()).as_secs_f64(), host);
+				tokio::time::sleep_until(slot).await;
+			}
+		}
+		match &self.inflight {
+			Some(sem) => sem.clone().acquire_owned().await.ok(),
+			None => None,
+		}
+	}
+
+	fn client(&self, source: &entities::sources::Model) -> Result<Client, FetchError> {
+		let transport = Transport::from(source);
+		let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some((t, client)) = clients.get(&source.id) {
+			if *t == transport {
+				return Ok(client.clone());
+			}
+		}
+		if transport.insecure {
+			warn!(target: "fetcher", "TLS verification is disabled for source '{}'", source.name);
+		}
+		let client = transport.client()?;
+		clients.insert(source.id, (transport, client.clone()));
+		Ok(client)
+	}
+
+	/// Seconds left before source can be requested again, if it's being throttled
+	pub fn backoff(&self, source_id: i64) -> Option<i64> {
+		let throttle = self.throttle.lock().unwrap_or_else(|e| e.into_inner());
+		let left = throttle.get(&source_id)?.until - Utc::now().timestamp();
+		if left > 0 { Some(left) } else { None }
+	}
+
+	fn throttled(&self, source: &entities::sources::Model, retry_after: Option<i64>) -> i64 {
+		let mut throttle = self.throttle.lock().unwrap_or_else(|e| e.into_inner());
+		let entry = throttle.entry(source.id).or_insert(Throttle { until: 0, strikes: 0 });
+		let wait = match retry_after {
+			Some(secs) => std::cmp::max(secs, 0),
+			None => std::cmp::min((source.interval as i64) << std::cmp::min(entry.strikes, 16), MAX_BACKOFF),
+		};
+		entry.strikes += 1;
+		entry.until = Utc::now().timestamp() + wait;
+		wait
+	}
+
+	/// Run source query on its database, keeping a pool open for each sql source
+	async fn query(&self, source: &entities::sources::Model) -> Result<serde_json::Value, FetchError> {
+		let cached = {
+			let databases = self.databases.lock().unwrap_or_else(|e| e.into_inner());
+			databases.get(&source.id).filter(|(uri, _)| *uri == source.url).map(|(_, db)| db.clone())
+		};
+		let db = match cached {
+			Some(db) => db,
+			None => {
+				let db = SqlPool::connect(&source.url).await?;
+				self.databases.lock().unwrap_or_else(|e| e.into_inner())
+					.insert(source.id, (source.url.clone(), db.clone()));
+				db
+			},
+		};
+		db.rows(&source.body).await
+	}
+
+	/// Last payload fetched for source, if younger than `max_age` seconds
+	pub fn latest(&self, source_id: i64, max_age: i64) -> Option<Payload> {
+		let latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+		latest.get(&source_id)
+			.filter(|(at, _)| at.elapsed().as_secs() < max_age as u64)
+			.map(|(_, payload)| payload.clone())
+	}
+
+	fn remember(&self, source_id: i64, payload: &Payload) {
+		self.latest.lock().unwrap_or_else(|e| e.into_inner())
+			.insert(source_id, (Instant::now(), payload.clone()));
+	}
+
+	/// Request source payload, without conditional headers nor caching
+	pub async fn fetch(&self, source: &entities::sources::Model) -> Result<Payload, FetchError> {
+		let payload = match source.kind {
+			SourceKind::Http | SourceKind::Html => {
+				let client = self.client(source)?;
+				read(source.kind, self.tokens.send(&client, source, request(&client, source)).await?).await?
+			},
+			SourceKind::Sql => Payload::Json(self.query(source).await?),
+			SourceKind::Uptime => Payload::Json(uptime::check(&self.client(source)?, &source.url).await?),
+		};
+		self.remember(source.id, &payload);
+		Ok(payload)
+	}
+
+	/// Request source payload, sending validators from last response. A 304 returns the cached
+	/// payload. 429s (and 503s with Retry-After) make source back off.
+	pub async fn poll(&self, source: &entities::sources::Model) -> Result<Polled, FetchError> {
+		if !matches!(source.kind, SourceKind::Http | SourceKind::Html) {
+			let _permit = self.wait_turn(source).await;
+			return Ok(Polled { payload: self.fetch(source).await?, modified: true });
+		}
+		let client = self.client(source)?;
+		let _permit = self.wait_turn(source).await;
+		let mut req = request(&client, source);
+		{
+			let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+			if let Some(cached) = cache.get(&source.id).filter(|c| c.url == source.url) {
+				if let Some(etag) = &cached.etag {
+					req = req.header(header::IF_NONE_MATCH, etag.clone());
+				}
+				if let Some(last_modified) = &cached.last_modified {
+					req = req.header(header::IF_MODIFIED_SINCE, last_modified.clone());
+				}
+			}
+		}
+
+		let res = self.tokens.send(&client, source, req).await?;
+		let status = res.status();
+		let retry = retry_after(&res);
+		if status == StatusCode::TOO_MANY_REQUESTS || (status == StatusCode::SERVICE_UNAVAILABLE && retry.is_some()) {
+			return Err(FetchError::Throttled(self.throttled(source, retry)));
+		}
+		self.throttle.lock().unwrap_or_else(|e| e.into_inner()).remove(&source.id);
+
+		if status == StatusCode::NOT_MODIFIED {
+			let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+			if let Some(cached) = cache.get(&source.id).filter(|c| c.url == source.url) {
+				self.remember(source.id, &cached.payload);
+				return Ok(Polled { payload: cached.payload.clone(), modified: false });
+			}
+			return Err(FetchError::NotFound("got 304 but no payload is cached".into()));
+		}
+
+		let etag = res.headers().get(header::ETAG).cloned();
+		let last_modified = res.headers().get(header::LAST_MODIFIED).cloned();
+		let payload = read(source.kind, res).await?;
+		self.remember(source.id, &payload);
+		let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+		if etag.is_some() || last_modified.is_some() {
+			cache.insert(source.id, Cached { url: source.url.clone(), etag, last_modified, payload: payload.clone() });
+		} else {
+			cache.remove(&source.id);
+		}
+		Ok(Polled { payload, modified: true })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn siblings_share_limits_but_not_sources() {
+		let fetcher = Fetcher::limited(4, 10);
+		let sibling = fetcher.sibling();
+		assert!(Arc::ptr_eq(fetcher.inflight.as_ref().unwrap(), sibling.inflight.as_ref().unwrap()));
+		assert!(Arc::ptr_eq(&fetcher.next_slot, &sibling.next_slot));
+		assert!(Arc::ptr_eq(&fetcher.tokens, &sibling.tokens));
+		assert_eq!(sibling.host_rate, 10);
+		fetcher.remember(3, &Payload::Json(serde_json::json!(1)));
+		assert!(fetcher.latest(3, 60).is_some());
+		assert!(sibling.latest(3, 60).is_none());
+	}
+
+	fn json(polled: &Polled) -> &serde_json::Value {
+		match &polled.payload {
+			Payload::Json(v) => v,
+			Payload::Html(_) => panic!("expected json payload"),
+		}
+	}
+
+	#[tokio::test]
+	async fn not_modified_reuses_cached_payload() {
+		let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let count = sent.clone();
+		let url = crate::worker::test_server(move |req| {
+			let sent = sent.clone();
+			async move {
+				if req.headers().get(header::IF_NONE_MATCH).map(|v| v == "\"v1\"").unwrap_or(false) {
+					return hyper::Response::builder().status(304).body(hyper::Body::empty()).unwrap();
+				}
+				sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+				hyper::Response::builder().header("ETag", "\"v1\"").body(r#"{"n": 1}"#.into()).unwrap()
+			}
+		}).await;
+		let fetcher = Fetcher::default();
+		let source = entities::sources::Model { id: 1, url: url.clone(), ..Default::default() };
+		let first = fetcher.poll(&source).await.unwrap();
+		assert!(first.modified);
+		let second = fetcher.poll(&source).await.unwrap();
+		assert!(!second.modified);
+		assert_eq!(json(&second), &serde_json::json!({"n": 1}));
+		assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+		// validators are not sent once source url changes
+		let moved = entities::sources::Model { url: format!("{}/other", url), ..source };
+		assert!(fetcher.poll(&moved).await.unwrap().modified);
+		assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn throttled_sources_back_off() {
+		let url = crate::worker::test_server(|req| async move {
+			let builder = hyper::Response::builder();
+			match req.uri().path() {
+				"/later" => builder.status(429).header("Retry-After", "120").body(hyper::Body::empty()),
+				"/busy" => builder.status(429).body(hyper::Body::empty()),
+				"/down" => builder.status(503).header("Retry-After", "30").body(hyper::Body::empty()),
+				_ => builder.body("{}".into()),
+			}.unwrap()
+		}).await;
+		let fetcher = Fetcher::default();
+		let source = |path: &str| entities::sources::Model { id: 1, interval: 10, url: format!("{}{}", url, path), ..Default::default() };
+
+		assert!(matches!(fetcher.poll(&source("/later")).await, Err(FetchError::Throttled(120))));
+		assert!(matches!(fetcher.backoff(1), Some(119..=120)));
+		assert!(matches!(fetcher.poll(&source("/down")).await, Err(FetchError::Throttled(30))));
+		// without Retry-After, wait doubles with each strike
+		assert!(matches!(fetcher.poll(&source("/busy")).await, Err(FetchError::Throttled(40))));
+		assert!(matches!(fetcher.poll(&source("/busy")).await, Err(FetchError::Throttled(80))));
+		assert!(fetcher.backoff(2).is_none());
+
+		assert!(fetcher.poll(&source("/")).await.is_ok());
+		assert!(fetcher.backoff(1).is_none());
+	}
+
+	#[tokio::test]
+	async fn same_host_requests_are_spaced_out() {
+		// 600 per minute: one every 100ms
+		let fetcher = Fetcher::limited(0, 600);
+		let source = entities::sources::Model { url: "http://example.com/a".into(), ..Default::default() };
+		let other = entities::sources::Model { url: "http://example.org/a".into(), ..Default::default() };
+		let start = Instant::now();
+		let mut turns = vec![];
+		for _ in 0..3 {
+			fetcher.wait_turn(&source).await;
+			turns.push(start.elapsed());
+		}
+		fetcher.wait_turn(&other).await;
+		assert!(start.elapsed() < turns[2] + Duration::from_millis(50));
+		assert!(turns[0] < Duration::from_millis(50));
+		for pair in turns.windows(2) {
+			assert!(pair[1] - pair[0] >= Duration::from_millis(95));
+		}
+		// a source rate overrides the host default
+		let fast = entities::sources::Model { url: "http://example.net/".into(), rate_limit: 6000, ..Default::default() };
+		let start = Instant::now();
+		for _ in 0..3 {
+			fetcher.wait_turn(&fast).await;
+		}
+		assert!(start.elapsed() < Duration::from_millis(50));
+	}
+
+	#[tokio::test]
+	async fn concurrency_never_exceeds_limit() {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		let fetcher = Arc::new(Fetcher::limited(2, 0));
+		let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+		let tasks : Vec<_> = (0..6).map(|i| {
+			let (fetcher, running, peak) = (fetcher.clone(), running.clone(), peak.clone());
+			tokio::spawn(async move {
+				let source = entities::sources::Model { url: format!("http://host{}.example/", i), ..Default::default() };
+				let _permit = fetcher.wait_turn(&source).await;
+				peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+				tokio::time::sleep(Duration::from_millis(20)).await;
+				running.fetch_sub(1, Ordering::SeqCst);
+			})
+		}).collect();
+		for task in tasks {
+			task.await.unwrap();
+		}
+		assert_eq!(peak.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn source_proxy_is_used() {
+		let proxy = crate::worker::test_server(|req| async move {
+			// proxied requests carry the absolute url of the target
+			hyper::Response::new(format!(r#"{{"via": "{}"}}"#, req.uri()).into())
+		}).await;
+		let fetcher = Fetcher::default();
+		let mut source = entities::sources::Model { id: 1, url: "http://dashboard.invalid/data".into(), ..Default::default() };
+		assert!(fetcher.fetch(&source).await.is_err());
+		// client is rebuilt once transport options change
+		source.proxy = proxy;
+		match fetcher.fetch(&source).await.unwrap() {
+			Payload::Json(v) => assert_eq!(v, serde_json::json!({"via": "http://dashboard.invalid/data"})),
+			Payload::Html(_) => panic!("expected json payload"),
+		}
+	}
+}
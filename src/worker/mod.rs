@@ -1,5 +1,43 @@
 pub mod surveyor;
 pub mod visualizer;
+pub mod spool;
+pub mod telemetry;
+pub mod probe;
+pub mod fetcher;
+pub mod sql;
+pub mod uptime;
+pub mod oauth;
+pub mod chain;
+pub mod pruner;
+pub mod rollup;
+pub mod transfer;
+
+pub use surveyor::{surveyor_loop, SurveyorConfig};
+pub use visualizer::{AppState, AppStateView, BackgroundAction, DeleteTarget, RangeEdit, RangeCount};
+
+/// Serve http on a random local port, answering every request with `handler`, for tests.
+/// Returns server base url.
+#[cfg(test)]
+pub async fn test_server<F, R>(handler: F) -> String
+where
+	F: Fn(hyper::Request<hyper::Body>) -> R + Send + Sync + 'static,
+	R: std::future::Future<Output = hyper::Response<hyper::Body>> + Send + 'static,
+{
+	use std::{convert::Infallible, sync::Arc};
+	use hyper::service::{make_service_fn, service_fn};
+	let handler = Arc::new(handler);
+	let make_svc = make_service_fn(move |_conn| {
+		let handler = handler.clone();
+		async move {
+			Ok::<_, Infallible>(service_fn(move |req| {
+				let res = handler(req);
+				async move { Ok::<_, Infallible>(res.await) }
+			}))
+		}
+	});
+	let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+	let url = format!("http://{}", server.local_addr());
+	tokio::spawn(server);
+	url
+}
 
-pub use surveyor::surveyor_loop;
-pub use visualizer::{AppState, AppStateView, BackgroundAction};
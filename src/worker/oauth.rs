@@ -0,0 +1,220 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use chrono::Utc;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::data::{entities, FetchError};
+
+// refresh tokens this many seconds before they expire
+const EXPIRY_MARGIN : i64 = 60;
+// assumed lifetime of tokens when server doesn't tell
+const DEFAULT_LIFETIME : i64 = 3600;
+
+/// Client credentials of a source: sources sharing them share tokens
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+	token_url: String,
+	client_id: String,
+	client_secret: String,
+	scope: String,
+}
+
+impl Credentials {
+	pub fn of(source: &entities::sources::Model) -> Option<Self> {
+		if source.token_url.is_empty() {
+			return None;
+		}
+		Some(Credentials {
+			token_url: source.token_url.clone(),
+			client_id: source.client_id.clone(),
+			client_secret: source.client_secret.clone(),
+			scope: source.scope.clone(),
+		})
+	}
+
+	/// Secret is either `env:VARIABLE`, `file:/path/to/secret` or the secret itself
+	fn secret(&self) -> Result<String, FetchError> {
+		if let Some(var) = self.client_secret.strip_prefix("env:") {
+			std::env::var(var).map_err(|e| FetchError::AuthError(format!("could not read secret from ${}: {}", var, e)))
+		} else if let Some(path) = self.client_secret.strip_prefix("file:") {
+			Ok(std::fs::read_to_string(path)?.trim().to_string())
+		} else {
+			Ok(self.client_secret.clone())
+		}
+	}
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	access_token: String,
+	expires_in: Option<i64>,
+}
+
+struct Token {
+	access_token: String,
+	expires_at: i64,
+}
+
+/// Bearer tokens obtained with client credentials grant, refreshed when close to expiry
+#[derive(Default)]
+pub struct TokenCache {
+	tokens: Mutex<HashMap<Credentials, Arc<tokio::sync::Mutex<Option<Token>>>>>,
+}
+
+impl TokenCache {
+	/// Get a valid token, requesting a new one if missing, expiring or equal to `stale`.
+	/// Only one request per credentials is made at a time, others wait for its result.
+	async fn token(&self, client: &Client, creds: &Credentials, stale: Option<&str>) -> Result<String, FetchError> {
+		let slot = self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+			.entry(creds.clone())
+			.or_default()
+			.clone();
+		let mut slot = slot.lock().await;
+		if let Some(token) = slot.as_ref() {
+			if token.expires_at - EXPIRY_MARGIN > Utc::now().timestamp() && Some(token.access_token.as_str()) != stale {
+				return Ok(token.access_token.clone());
+			}
+		}
+
+		let mut form = vec![("grant_type", "client_credentials")];
+		if !creds.scope.is_empty() {
+			form.push(("scope", creds.scope.as_str()));
+		}
+		let res = client.post(&creds.token_url)
+			.basic_auth(&creds.client_id, Some(creds.secret()?))
+			.form(&form)
+			.send().await?;
+		if !res.status().is_success() {
+			let status = res.status();
+			let body = res.text().await.unwrap_or_default();
+			*slot = None;
+			return Err(FetchError::AuthError(format!("token endpoint replied {}: {}", status, body)));
+		}
+		let res : TokenResponse = res.json().await?;
+		info!(target: "oauth", "Obtained token for client '{}' from {}", creds.client_id, creds.token_url);
+		*slot = Some(Token {
+			access_token: res.access_token.clone(),
+			expires_at: Utc::now().timestamp() + res.expires_in.unwrap_or(DEFAULT_LIFETIME),
+		});
+		Ok(res.access_token)
+	}
+
+	/// Send request with a bearer token if source has credentials. If server replies 401 the
+	/// token is refreshed and request retried once.
+	pub async fn send(&self, client: &Client, source: &entities::sources::Model, req: RequestBuilder) -> Result<Response, FetchError> {
+		let Some(creds) = Credentials::of(source) else {
+			return Ok(req.send().await?);
+		};
+		let retry = req.try_clone();
+		let token = self.token(client, &creds, None).await?;
+		let res = req.bearer_auth(&token).send().await?;
+		match (res.status(), retry) {
+			(StatusCode::UNAUTHORIZED, Some(retry)) => {
+				let token = self.token(client, &creds, Some(&token)).await?;
+				Ok(retry.bearer_auth(token).send().await?)
+			},
+			_ => Ok(res),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+	use crate::worker::test_server;
+
+	/// Server issuing tokens `t1`, `t2`... lasting `lifetime` seconds on /token, and replying on
+	/// /data with the bearer token it got, or 401 if it's `rejected`. Returns url and token count.
+	async fn server(lifetime: i64, rejected: &'static [&'static str]) -> (String, Arc<AtomicUsize>) {
+		let issued = Arc::new(AtomicUsize::new(0));
+		let count = issued.clone();
+		let url = test_server(move |req| {
+			let issued = issued.clone();
+			async move {
+				if req.uri().path() == "/token" {
+					assert_eq!(req.headers()[hyper::header::AUTHORIZATION], "Basic aWQ6c2VjcmV0"); // id:secret
+					let n = issued.fetch_add(1, Ordering::SeqCst) + 1;
+					return hyper::Response::new(format!(r#"{{"access_token":"t{}","expires_in":{}}}"#, n, lifetime).into());
+				}
+				let token = req.headers().get(hyper::header::AUTHORIZATION)
+					.and_then(|v| v.to_str().ok())
+					.and_then(|v| v.strip_prefix("Bearer "))
+					.unwrap_or_default()
+					.to_string();
+				let mut res = hyper::Response::new(token.clone().into());
+				if rejected.contains(&token.as_str()) {
+					*res.status_mut() = hyper::StatusCode::UNAUTHORIZED;
+				}
+				res
+			}
+		}).await;
+		(url, count)
+	}
+
+	fn source(url: &str) -> entities::sources::Model {
+		entities::sources::Model {
+			url: format!("{}/data", url),
+			token_url: format!("{}/token", url),
+			client_id: "id".into(),
+			client_secret: "secret".into(),
+			..Default::default()
+		}
+	}
+
+	async fn get(cache: &TokenCache, source: &entities::sources::Model) -> (StatusCode, String) {
+		let client = Client::new();
+		let res = cache.send(&client, source, client.get(&source.url)).await.unwrap();
+		(res.status(), res.text().await.unwrap())
+	}
+
+	#[tokio::test]
+	async fn token_is_cached_until_expiry() {
+		let (url, issued) = server(3600, &[]).await;
+		let cache = TokenCache::default();
+		let src = source(&url);
+		assert_eq!(get(&cache, &src).await, (StatusCode::OK, "t1".into()));
+		assert_eq!(get(&cache, &src).await, (StatusCode::OK, "t1".into()));
+		assert_eq!(issued.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn expiring_token_is_refreshed() {
+		// lifetime within margin: token is considered expired right away
+		let (url, issued) = server(EXPIRY_MARGIN / 2, &[]).await;
+		let cache = TokenCache::default();
+		let src = source(&url);
+		assert_eq!(get(&cache, &src).await.1, "t1");
+		assert_eq!(get(&cache, &src).await.1, "t2");
+		assert_eq!(issued.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn rejected_token_is_refreshed_and_request_retried_once() {
+		let (url, issued) = server(3600, &["t1"]).await;
+		let cache = TokenCache::default();
+		let src = source(&url);
+		assert_eq!(get(&cache, &src).await, (StatusCode::OK, "t2".into()));
+		assert_eq!(get(&cache, &src).await, (StatusCode::OK, "t2".into()));
+		assert_eq!(issued.load(Ordering::SeqCst), 2);
+
+		// a second rejection is returned as is, without asking for more tokens
+		let (url, issued) = server(3600, &["t1", "t2"]).await;
+		let cache = TokenCache::default();
+		let src = source(&url);
+		assert_eq!(get(&cache, &src).await.0, StatusCode::UNAUTHORIZED);
+		assert_eq!(issued.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn sources_without_credentials_get_no_token() {
+		let (url, issued) = server(3600, &[]).await;
+		let cache = TokenCache::default();
+		let src = entities::sources::Model { url: format!("{}/data", url), ..Default::default() };
+		assert_eq!(get(&cache, &src).await, (StatusCode::OK, "".into()));
+		assert_eq!(issued.load(Ordering::SeqCst), 0);
+	}
+}
@@ -0,0 +1,125 @@
+use sea_orm::{EntityTrait, QueryFilter, ColumnTrait, QueryOrder, Order};
+
+use crate::data::{self, entities, FetchError, Payload};
+
+use super::{fetcher::Fetcher, chain};
+
+/// Which source to probe: either one stored on db or an ad-hoc url with some queries
+pub enum ProbeTarget {
+	Stored { db_uri: String, source: String },
+	Adhoc { url: String, queries: Vec<String> },
+}
+
+async fn load(db_uri: &str, source: &str) -> Result<(entities::sources::Model, Vec<entities::metrics::Model>, Vec<entities::sources::Model>), FetchError> {
+	// probing never writes, not even pending migrations
+	let db = data::connect(db_uri, true).await?;
+	// all sources are loaded since chained sources may need them
+	let sources = entities::sources::Entity::find().all(&db).await?;
+	let Some(src) = sources.iter()
+		.find(|s| s.name == source || source.parse::<i64>().map(|id| id == s.id).unwrap_or(false))
+		.cloned() else {
+		return Err(FetchError::NotFound(format!("no source with id or name '{}'", source)));
+	};
+	let metrics = entities::metrics::Entity::find()
+		.filter(entities::metrics::Column::SourceId.eq(src.id))
+		.order_by(entities::metrics::Column::Position, Order::Asc)
+		.order_by(entities::metrics::Column::Id, Order::Asc)
+		.all(&db).await?;
+	Ok((src, metrics, sources))
+}
+
+/// What probing a source found: its payload and what each metric extracts from it
+pub struct ProbeReport {
+	pub source: entities::sources::Model,
+	pub payload: Payload,
+	pub values: Vec<(entities::metrics::Model, Result<Option<f64>, FetchError>)>,
+}
+
+/// Fetch a source once and extract its metrics. Nothing is written to the database.
+pub async fn inspect(target: ProbeTarget) -> Result<ProbeReport, FetchError> {
+	let (source, metrics, sources) = match target {
+		ProbeTarget::Stored { db_uri, source } => load(&db_uri, &source).await?,
+		ProbeTarget::Adhoc { url, queries } => (
+			entities::sources::Model { name: "adhoc".into(), url, ..Default::default() },
+			queries.into_iter()
+				.enumerate()
+				.map(|(i, q)| entities::metrics::Model { name: format!("#{}", i), query: q, ..Default::default() })
+				.collect(),
+			vec![],
+		),
+	};
+
+	let fetcher = Fetcher::default();
+	let source = chain::resolve(&fetcher, &source, &sources).await?;
+	let payload = fetcher.fetch(&source).await?;
+	let values = metrics.into_iter()
+		.map(|metric| {
+			let value = metric.extract(&payload);
+			(metric, value)
+		})
+		.collect();
+	Ok(ProbeReport { source, payload, values })
+}
+
+/// Fetch a source once, print its payload and what each metric extracts from it. Nothing is
+/// written to the database.
+pub async fn probe(target: ProbeTarget) -> Result<(), FetchError> {
+	let report = inspect(target).await?;
+	println!("source '{}' -> {}", report.source.name, report.source.url);
+	match &report.payload {
+		Payload::Json(value) => println!("{}", serde_json::to_string_pretty(value).unwrap_or_default()),
+		Payload::Html(doc) => println!("{}", doc.source),
+	}
+
+	println!();
+	if report.values.is_empty() {
+		println!("no metrics to extract");
+	}
+	for (metric, value) in report.values.iter() {
+		match value {
+			Ok(Some(v)) => println!("  {} [{}] = {}", metric.name, metric.query, v),
+			Ok(None) => println!("  {} [{}] = (not a number)", metric.name, metric.query),
+			Err(e) => println!("  {} [{}] ! {:?}", metric.name, metric.query, e),
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use sea_orm::{IntoActiveModel, PaginatorTrait};
+
+	use super::*;
+	use crate::worker::test_server;
+
+	#[tokio::test]
+	async fn probing_stored_source_writes_nothing() {
+		let url = test_server(|_| async { hyper::Response::new(r#"{"temp": 21.5, "name": "kitchen"}"#.into()) }).await;
+		let uri = data::test_db_uri("probe");
+		let db = data::connect(&uri, false).await.unwrap();
+		let source = entities::sources::Model { id: 1, name: "sensor".into(), url, ..Default::default() };
+		entities::sources::Entity::insert(source.into_active_model()).exec(&db).await.unwrap();
+		for (id, query) in [(1, "\"temp\""), (2, "\"name\"")] {
+			let metric = entities::metrics::Model { id, source_id: 1, name: format!("m{}", id), query: query.into(), position: id as i32, ..Default::default() };
+			entities::metrics::Entity::insert(metric.into_active_model()).exec(&db).await.unwrap();
+		}
+		let points = entities::points::Entity::find().count(&db).await.unwrap();
+
+		let report = inspect(ProbeTarget::Stored { db_uri: uri.clone(), source: "sensor".into() }).await.unwrap();
+		let values : Vec<_> = report.values.iter().map(|(m, v)| (m.id, v.as_ref().ok().cloned())).collect();
+		assert_eq!(values, vec![(1, Some(Some(21.5))), (2, None)]);
+		assert_eq!(entities::points::Entity::find().count(&db).await.unwrap(), points);
+		assert!(entities::payloads::Entity::find().one(&db).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn probing_refuses_outdated_databases() {
+		let url = test_server(|_| async { hyper::Response::new("{}".into()) }).await;
+		let uri = data::test_db_uri("probe-outdated");
+		let res = inspect(ProbeTarget::Stored { db_uri: uri.clone(), source: "1".into() }).await;
+		assert!(matches!(res, Err(FetchError::DbError(_))));
+		// an ad-hoc probe needs no database at all
+		let report = inspect(ProbeTarget::Adhoc { url, queries: vec!["\"x\"".into()] }).await.unwrap();
+		assert_eq!(report.values.len(), 1);
+	}
+}
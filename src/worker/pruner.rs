@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use sea_orm::{DatabaseConnection, EntityTrait, QueryFilter, ColumnTrait, PaginatorTrait, DbErr, sea_query::Query};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+
+use crate::data::entities;
+
+use super::rollup::{rollup, Stage};
+
+// seconds between pruning passes
+const PRUNE_INTERVAL : u64 = 600;
+// how many points are deleted with each statement, to not lock the db for long
+const PRUNE_BATCH : u64 = 1000;
+
+/// Days of points to keep for a metric: its own retention or `default`. Zero keeps everything.
+pub fn retention(metric: &entities::metrics::Model, default: i32) -> i32 {
+	metric.retention.unwrap_or(default)
+}
+
+fn cutoff(days: i32) -> f64 {
+	(Utc::now().timestamp() - days as i64 * 86400) as f64
+}
+
+/// How many points (raw or rolled up) each metric would lose if pruned now
+pub async fn report(db: &DatabaseConnection, default: i32) -> Result<Vec<(entities::metrics::Model, i32, u64)>, DbErr> {
+	let mut out = vec![];
+	for metric in entities::metrics::Entity::find().all(db).await? {
+		let days = retention(&metric, default);
+		let expired = if days > 0 {
+			entities::points::Entity::find()
+				.filter(entities::points::Column::MetricId.eq(metric.id))
+				.filter(entities::points::Column::X.lt(cutoff(days)))
+				.count(db).await?
+			+ entities::rollups::Entity::find()
+				.filter(entities::rollups::Column::MetricId.eq(metric.id))
+				.filter(entities::rollups::Column::X.lt(cutoff(days)))
+				.count(db).await?
+		} else { 0 };
+		out.push((metric, days, expired));
+	}
+	Ok(out)
+}
+
+/// Delete expired points of one metric in batches, then its expired rollups. Returns how many
+/// rows were removed, stops early between batches once `run` turns false.
+async fn prune_metric(db: &DatabaseConnection, metric: &entities::metrics::Model, days: i32, run: &watch::Receiver<bool>) -> Result<u64, DbErr> {
+	let before = cutoff(days);
+	let mut removed = 0;
+	loop {
+		if !*run.borrow() {
+			return Ok(removed);
+		}
+		// ids are picked in a subquery, so no rows are loaded and each statement stays bounded
+		let batch = Query::select()
+			.column(entities::points::Column::Id)
+			.from(entities::points::Entity)
+			.and_where(entities::points::Column::MetricId.eq(metric.id))
+			.and_where(entities::points::Column::X.lt(before))
+			.limit(PRUNE_BATCH)
+			.to_owned();
+		let res = entities::points::Entity::delete_many()
+			.filter(entities::points::Column::Id.in_subquery(batch))
+			.exec(db).await?;
+		removed += res.rows_affected;
+		if res.rows_affected < PRUNE_BATCH {
+			break;
+		}
+		tokio::task::yield_now().await; // let inserts through between batches
+	}
+	let res = entities::rollups::Entity::delete_many()
+		.filter(entities::rollups::Column::MetricId.eq(metric.id))
+		.filter(entities::rollups::Column::X.lt(before))
+		.exec(db).await?;
+	removed += res.rows_affected;
+	Ok(removed)
+}
+
+/// Delete expired points of every metric once, returns how many were removed. Stops early,
+/// leaving the rest for next pass, once `run` turns false.
+pub async fn prune(db: &DatabaseConnection, default: i32, run: &watch::Receiver<bool>) -> Result<u64, DbErr> {
+	let mut total = 0;
+	for metric in entities::metrics::Entity::find().all(db).await? {
+		let days = retention(&metric, default);
+		if days <= 0 {
+			continue;
+		}
+		if !*run.borrow() {
+			break;
+		}
+		let removed = prune_metric(db, &metric, days, run).await?;
+		if removed > 0 {
+			debug!(target: "pruner", "Removed {} points of '{}' older than {} days", removed, metric.name, days);
+		}
+		total += removed;
+	}
+	Ok(total)
+}
+
+/// Periodically roll up old points and delete expired ones
+pub async fn pruner_loop(db: DatabaseConnection, default: i32, stages: Vec<Stage>, mut run: watch::Receiver<bool>, index: usize) {
+	while *run.borrow() {
+		match rollup(&db, &stages, &run).await {
+			Ok(0) => {},
+			Ok(rolled) => info!(target: "pruner", "[{}] Rolled up {} rows", index, rolled),
+			Err(e) => error!(target: "pruner", "[{}] Could not roll up old points: {:?}", index, e),
+		}
+		match prune(&db, default, &run).await {
+			Ok(0) => {},
+			Ok(removed) => info!(target: "pruner", "[{}] Removed {} expired points", index, removed),
+			Err(e) => error!(target: "pruner", "[{}] Could not prune expired points: {:?}", index, e),
+		}
+		tokio::select! {
+			_ = tokio::time::sleep(Duration::from_secs(PRUNE_INTERVAL)) => {},
+			_ = run.changed() => {},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sea_orm::{ActiveValue::NotSet, Set};
+	use crate::data::{test_db, test_metric};
+
+	#[tokio::test]
+	async fn prune_metric_removes_only_expired_points() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |_| {}).await;
+		let old = cutoff(10) - 60.0;
+		let recent = cutoff(1);
+		// more than one batch of expired points
+		let points : Vec<_> = (0..PRUNE_BATCH + 5)
+			.map(|i| old - i as f64)
+			.chain([recent, recent + 1.0])
+			.map(|x| entities::points::ActiveModel {
+				id: NotSet, metric_id: Set(metric.id), x: Set(x), y: Set(1.0), flagged: Set(false),
+			})
+			.collect();
+		for chunk in points.chunks(200) {
+			entities::points::Entity::insert_many(chunk.to_vec()).exec(&db).await.unwrap();
+		}
+		let rollup = entities::rollups::ActiveModel {
+			id: NotSet, metric_id: Set(metric.id), resolution: Set(60), x: Set(old),
+			min: Set(1.0), max: Set(1.0), avg: Set(1.0), count: Set(1),
+		};
+		entities::rollups::Entity::insert(rollup).exec(&db).await.unwrap();
+
+		assert_eq!(report(&db, 7).await.unwrap()[0].2, PRUNE_BATCH + 6);
+		// a worker shutting down doesn't start deleting
+		let (_stop_tx, stopped) = watch::channel(false);
+		assert_eq!(prune(&db, 7, &stopped).await.unwrap(), 0);
+		assert_eq!(prune_metric(&db, &metric, 7, &stopped).await.unwrap(), 0);
+		let (_run_tx, run) = watch::channel(true);
+		assert_eq!(prune_metric(&db, &metric, 7, &run).await.unwrap(), PRUNE_BATCH + 6);
+		let left = entities::points::Entity::find().all(&db).await.unwrap();
+		assert_eq!(left.iter().map(|p| p.x).collect::<Vec<_>>(), vec![recent, recent + 1.0]);
+		assert_eq!(entities::rollups::Entity::find().count(&db).await.unwrap(), 0);
+	}
+
+	#[tokio::test]
+	async fn prune_skips_metrics_keeping_everything() {
+		let db = test_db().await;
+		test_metric(&db, |m| m.retention = Some(0)).await;
+		let point = entities::points::ActiveModel {
+			id: NotSet, metric_id: Set(1), x: Set(0.0), y: Set(1.0), flagged: Set(false),
+		};
+		entities::points::Entity::insert(point).exec(&db).await.unwrap();
+		let (_run_tx, run) = watch::channel(true);
+		assert_eq!(prune(&db, 30, &run).await.unwrap(), 0);
+		assert_eq!(entities::points::Entity::find().count(&db).await.unwrap(), 1);
+	}
+}
+
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use tokio::sync::watch;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder, QuerySelect, ColumnTrait, TransactionTrait, Set, ActiveValue::NotSet, Order};
+
+use crate::data::entities;
+
+// how many rows are rolled with each transaction
+const ROLLUP_BATCH : u64 = 1000;
+
+pub const MINUTE : i32 = 60;
+pub const HOUR : i32 = 3600;
+pub const DAY : i32 = 86400;
+
+/// Roll data into buckets `resolution` seconds wide once it's older than `after` days
+#[derive(Debug, Clone, Copy)]
+pub struct Stage {
+	pub resolution: i32,
+	pub after: i64,
+}
+
+/// Enabled stages must roll data into coarser buckets only once it's older, otherwise a coarse
+/// stage would swallow data before finer ones get to it
+pub fn check_stages(stages: &[Stage]) -> Result<(), String> {
+	let mut enabled : Vec<&Stage> = stages.iter().filter(|s| s.after > 0).collect();
+	enabled.sort_by_key(|s| s.resolution);
+	for pair in enabled.windows(2) {
+		if pair[1].after <= pair[0].after {
+			return Err(format!(
+				"{}s rollups start after {} days, must be later than {} days of {}s rollups",
+				pair[1].resolution, pair[1].after, pair[0].after, pair[0].resolution
+			));
+		}
+	}
+	Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+	min: f64,
+	max: f64,
+	sum: f64,
+	count: i64,
+}
+
+impl Bucket {
+	fn add(&mut self, other: Bucket) {
+		self.min = self.min.min(other.min);
+		self.max = self.max.max(other.max);
+		self.sum += other.sum;
+		self.count += other.count;
+	}
+}
+
+impl From<&entities::points::Model> for Bucket {
+	fn from(p: &entities::points::Model) -> Self {
+		Bucket { min: p.y, max: p.y, sum: p.y, count: 1 }
+	}
+}
+
+impl From<&entities::rollups::Model> for Bucket {
+	fn from(r: &entities::rollups::Model) -> Self {
+		Bucket { min: r.min, max: r.max, sum: r.avg * r.count as f64, count: r.count }
+	}
+}
+
+fn bucket_start(x: f64, resolution: i32) -> i64 {
+	(x as i64).div_euclid(resolution as i64) * resolution as i64
+}
+
+/// Add buckets to rollups of metric, merging with rollups already stored for same bucket
+async fn store<C: ConnectionTrait>(db: &C, metric_id: i64, resolution: i32, buckets: BTreeMap<i64, Bucket>) -> Result<(), DbErr> {
+	for (x, mut bucket) in buckets {
+		let existing = entities::rollups::Entity::find()
+			.filter(entities::rollups::Column::MetricId.eq(metric_id))
+			.filter(entities::rollups::Column::Resolution.eq(resolution))
+			.filter(entities::rollups::Column::X.eq(x as f64))
+			.one(db).await?;
+		let id = match existing {
+			Some(r) => { bucket.add(Bucket::from(&r)); Set(r.id) },
+			None => NotSet,
+		};
+		let model = entities::rollups::ActiveModel {
+			id, metric_id: Set(metric_id), resolution: Set(resolution), x: Set(x as f64),
+			min: Set(bucket.min), max: Set(bucket.max), avg: Set(bucket.sum / bucket.count as f64), count: Set(bucket.count),
+		};
+		match model.id {
+			NotSet => { entities::rollups::Entity::insert(model).exec(db).await?; },
+			_ => { entities::rollups::Entity::update(model).exec(db).await?; },
+		}
+	}
+	Ok(())
+}
+
+/// Roll raw points older than `before` into rollups, deleting them. Flagged points are left alone.
+/// Stops between batches once `run` turns false.
+async fn roll_points(db: &DatabaseConnection, metric_id: i64, resolution: i32, before: f64, run: &watch::Receiver<bool>) -> Result<u64, DbErr> {
+	let mut rolled = 0;
+	loop {
+		if !*run.borrow() {
+			return Ok(rolled);
+		}
+		let txn = db.begin().await?;
+		let points = entities::points::Entity::find()
+			.filter(entities::points::Column::MetricId.eq(metric_id))
+			.filter(entities::points::Column::Flagged.eq(false))
+			.filter(entities::points::Column::X.lt(before))
+			.order_by(entities::points::Column::X, Order::Asc)
+			.limit(ROLLUP_BATCH)
+			.all(&txn).await?;
+		let mut buckets : BTreeMap<i64, Bucket> = BTreeMap::new();
+		for p in points.iter() {
+			buckets.entry(bucket_start(p.x, resolution))
+				.and_modify(|b| b.add(Bucket::from(p)))
+				.or_insert_with(|| Bucket::from(p));
+		}
+		store(&txn, metric_id, resolution, buckets).await?;
+		entities::points::Entity::delete_many()
+			.filter(entities::points::Column::Id.is_in(points.iter().map(|p| p.id)))
+			.exec(&txn).await?;
+		txn.commit().await?;
+		rolled += points.len() as u64;
+		if (points.len() as u64) < ROLLUP_BATCH {
+			return Ok(rolled);
+		}
+	}
+}
+
+/// Roll rollups at a finer resolution older than `before` into coarser ones, deleting them.
+/// Stops between batches once `run` turns false.
+async fn roll_rollups(db: &DatabaseConnection, metric_id: i64, from: i32, resolution: i32, before: f64, run: &watch::Receiver<bool>) -> Result<u64, DbErr> {
+	let mut rolled = 0;
+	loop {
+		if !*run.borrow() {
+			return Ok(rolled);
+		}
+		let txn = db.begin().await?;
+		let rollups = entities::rollups::Entity::find()
+			.filter(entities::rollups::Column::MetricId.eq(metric_id))
+			.filter(entities::rollups::Column::Resolution.eq(from))
+			.filter(entities::rollups::Column::X.lt(before))
+			.order_by(entities::rollups::Column::X, Order::Asc)
+			.limit(ROLLUP_BATCH)
+			.all(&txn).await?;
+		let mut buckets : BTreeMap<i64, Bucket> = BTreeMap::new();
+		for r in rollups.iter() {
+			buckets.entry(bucket_start(r.x, resolution))
+				.and_modify(|b| b.add(Bucket::from(r)))
+				.or_insert_with(|| Bucket::from(r));
+		}
+		store(&txn, metric_id, resolution, buckets).await?;
+		entities::rollups::Entity::delete_many()
+			.filter(entities::rollups::Column::Id.is_in(rollups.iter().map(|r| r.id)))
+			.exec(&txn).await?;
+		txn.commit().await?;
+		rolled += rollups.len() as u64;
+		if (rollups.len() as u64) < ROLLUP_BATCH {
+			return Ok(rolled);
+		}
+	}
+}
+
+/// Run each stage on every metric, finest resolution first. Each stage rolls what previous one
+/// produced (raw points for the first one). Returns how many rows were rolled up. Stops early,
+/// leaving the rest for next pass, once `run` turns false.
+pub async fn rollup(db: &DatabaseConnection, stages: &[Stage], run: &watch::Receiver<bool>) -> Result<u64, DbErr> {
+	let mut stages : Vec<Stage> = stages.iter().filter(|s| s.after > 0).copied().collect();
+	stages.sort_by_key(|s| s.resolution);
+	if stages.is_empty() {
+		return Ok(0);
+	}
+	let now = Utc::now().timestamp();
+	let mut rolled = 0;
+	for metric in entities::metrics::Entity::find().all(db).await? {
+		let mut from = None;
+		for stage in stages.iter() {
+			// only roll complete buckets, late points are still merged if they arrive
+			let before = bucket_start((now - stage.after * 86400) as f64, stage.resolution) as f64;
+			rolled += match from {
+				None => roll_points(db, metric.id, stage.resolution, before, run).await?,
+				Some(finer) => roll_rollups(db, metric.id, finer, stage.resolution, before, run).await?,
+			};
+			from = Some(stage.resolution);
+		}
+	}
+	Ok(rolled)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sea_orm::PaginatorTrait;
+	use crate::data::{test_db, test_metric};
+
+	#[test]
+	fn buckets_start_at_resolution_multiples() {
+		assert_eq!(bucket_start(0.0, MINUTE), 0);
+		assert_eq!(bucket_start(59.9, MINUTE), 0);
+		assert_eq!(bucket_start(60.0, MINUTE), 60);
+		assert_eq!(bucket_start(7261.0, HOUR), 7200);
+		assert_eq!(bucket_start(-1.0, MINUTE), -60);
+	}
+
+	#[test]
+	fn buckets_merge_aggregates() {
+		let mut bucket = Bucket { min: 1.0, max: 3.0, sum: 4.0, count: 2 };
+		let rollup = entities::rollups::Model { id: 1, metric_id: 1, resolution: MINUTE, x: 0.0, min: 0.0, max: 2.0, avg: 1.0, count: 4 };
+		bucket.add(Bucket::from(&rollup));
+		assert_eq!((bucket.min, bucket.max, bucket.sum, bucket.count), (0.0, 3.0, 8.0, 6));
+	}
+
+	#[test]
+	fn stages_must_start_later_as_resolution_grows() {
+		let stage = |resolution, after| Stage { resolution, after };
+		assert!(check_stages(&[stage(MINUTE, 1), stage(HOUR, 7), stage(DAY, 30)]).is_ok());
+		assert!(check_stages(&[stage(DAY, 30), stage(MINUTE, 1)]).is_ok());
+		assert!(check_stages(&[stage(MINUTE, 7), stage(HOUR, 0), stage(DAY, 1)]).is_err());
+		assert!(check_stages(&[stage(MINUTE, 7), stage(HOUR, 7)]).is_err());
+		// disabled stages don't count
+		assert!(check_stages(&[stage(MINUTE, 7), stage(HOUR, 0), stage(DAY, 30)]).is_ok());
+	}
+
+	#[tokio::test]
+	async fn old_points_are_rolled_through_stages() {
+		let db = test_db().await;
+		test_metric(&db, |_| {}).await;
+		let day = bucket_start((Utc::now().timestamp() - 10 * 86400) as f64, DAY) as f64;
+		let points : Vec<_> = [(0.0, 1.0), (30.0, 3.0), (60.0, 5.0), (HOUR as f64, 7.0)].into_iter()
+			.map(|(dx, y)| entities::points::ActiveModel {
+				id: NotSet, metric_id: Set(1), x: Set(day + dx), y: Set(y), flagged: Set(false),
+			})
+			.chain([entities::points::ActiveModel {
+				id: NotSet, metric_id: Set(1), x: Set(day + 10.0), y: Set(100.0), flagged: Set(true),
+			}])
+			.collect();
+		entities::points::Entity::insert_many(points).exec(&db).await.unwrap();
+
+		let minute = [Stage { resolution: MINUTE, after: 1 }];
+		// a worker shutting down doesn't start rolling
+		let (_stop_tx, stopped) = watch::channel(false);
+		assert_eq!(rollup(&db, &minute, &stopped).await.unwrap(), 0);
+		let (_run_tx, run) = watch::channel(true);
+		assert_eq!(rollup(&db, &minute, &run).await.unwrap(), 4);
+		let rollups = entities::rollups::Entity::find()
+			.order_by(entities::rollups::Column::X, Order::Asc)
+			.all(&db).await.unwrap();
+		let summary : Vec<_> = rollups.iter().map(|r| (r.x - day, r.min, r.max, r.avg, r.count)).collect();
+		assert_eq!(summary, vec![(0.0, 1.0, 3.0, 2.0, 2), (60.0, 5.0, 5.0, 5.0, 1), (HOUR as f64, 7.0, 7.0, 7.0, 1)]);
+		// flagged points are left for the user to edit
+		assert_eq!(entities::points::Entity::find().count(&db).await.unwrap(), 1);
+
+		let all = [Stage { resolution: MINUTE, after: 1 }, Stage { resolution: HOUR, after: 2 }, Stage { resolution: DAY, after: 3 }];
+		assert_eq!(rollup(&db, &all, &run).await.unwrap(), 3 + 2);
+		let rollups = entities::rollups::Entity::find().all(&db).await.unwrap();
+		assert_eq!(rollups.len(), 1);
+		assert_eq!((rollups[0].x, rollups[0].resolution, rollups[0].avg, rollups[0].count), (day, DAY, 4.0, 4));
+	}
+}
+
@@ -0,0 +1,270 @@
+use std::{path::PathBuf, io::{BufRead, BufReader, Write}, fs::{File, OpenOptions}, time::{Duration, Instant}};
+
+use sea_orm::{DatabaseConnection, EntityTrait, Set, ActiveValue::NotSet, DbErr};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+
+use crate::data::{entities, FetchError};
+
+// how many spooled points are sent to db with each insert while replaying
+const REPLAY_CHUNK : usize = 100;
+// how long replay waits after db refused an insert
+const RETRY_AFTER : Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpooledPoint {
+	pub metric_id: i64,
+	pub x: f64,
+	pub y: f64,
+	#[serde(default)]
+	pub flagged: bool,
+}
+
+impl From<&SpooledPoint> for entities::points::ActiveModel {
+	fn from(p: &SpooledPoint) -> Self {
+		entities::points::ActiveModel { id: NotSet, metric_id: Set(p.metric_id), x: Set(p.x), y: Set(p.y), flagged: Set(p.flagged) }
+	}
+}
+
+/// Append-only file holding points which could not be inserted, one json object per line.
+/// Points are replayed in the same order they were spooled once the db is reachable again.
+pub struct Spool {
+	path: PathBuf,
+	capacity: usize,
+	depth: usize,
+	failed_at: Option<Instant>,
+}
+
+impl Spool {
+	pub fn open(path: PathBuf, capacity: usize) -> Result<Self, FetchError> {
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		let depth = match File::open(&path) {
+			Ok(f) => BufReader::new(f).lines().count(),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+			Err(e) => return Err(e.into()),
+		};
+		Ok(Spool { path, capacity, depth, failed_at: None })
+	}
+
+	/// Spool file for a database, named after its uri so points are never replayed into another
+	/// database when `--db` list changes
+	pub fn file_name(db_uri: &str) -> String {
+		// FNV-1a, std hashers aren't guaranteed to give same result across releases
+		let hash = db_uri.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+		format!("spool-{:016x}.jsonl", hash)
+	}
+
+	pub fn default_dir() -> PathBuf {
+		dirs::data_dir()
+			.unwrap_or(PathBuf::from("."))
+			.join("dashboard")
+	}
+
+	pub fn depth(&self) -> usize {
+		self.depth
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.depth == 0
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.depth >= self.capacity
+	}
+
+	/// Store a point at the end of the spool, failing if spool is already full
+	pub fn push(&mut self, point: SpooledPoint) -> Result<(), FetchError> {
+		if self.is_full() {
+			return Err(std::io::Error::other("spool is full").into());
+		}
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		writeln!(file, "{}", serde_json::to_string(&point).unwrap_or_default())?;
+		self.depth += 1;
+		Ok(())
+	}
+
+	/// Remember that db refused an insert, replay will wait a bit before trying again
+	pub fn insert_failed(&mut self) {
+		self.failed_at = Some(Instant::now());
+	}
+
+	/// Whether there's something to replay and db didn't fail an insert recently
+	pub fn should_replay(&self) -> bool {
+		!self.is_empty() && self.failed_at.map(|t| t.elapsed() >= RETRY_AFTER).unwrap_or(true)
+	}
+
+	/// Up to `count` points after the first `skip` lines, and how many lines were read to find them.
+	/// Lines broken by crashes mid-write are skipped but still counted.
+	fn peek(&self, skip: usize, count: usize) -> Result<(usize, Vec<SpooledPoint>), FetchError> {
+		let mut lines = 0;
+		let mut points = vec![];
+		for line in BufReader::new(File::open(&self.path)?).lines().skip(skip).map_while(Result::ok) {
+			if points.len() >= count {
+				break;
+			}
+			lines += 1;
+			if let Ok(p) = serde_json::from_str(&line) {
+				points.push(p);
+			}
+		}
+		Ok((lines, points))
+	}
+
+	/// Forget first `lines` lines of the spool, once their points are stored. Remaining points are
+	/// rewritten to a new file which is then swapped in, so a crash can't lose the spool.
+	fn drain(&mut self, lines: usize) -> Result<(), FetchError> {
+		let tmp_path = self.path.with_extension("jsonl.tmp");
+		let mut tmp = File::create(&tmp_path)?;
+		let mut depth = 0;
+		for line in BufReader::new(File::open(&self.path)?).lines().skip(lines).map_while(Result::ok) {
+			writeln!(tmp, "{}", line)?;
+			depth += 1;
+		}
+		tmp.sync_all()?;
+		std::fs::rename(&tmp_path, &self.path)?;
+		self.depth = depth;
+		Ok(())
+	}
+}
+
+/// Insert spooled points in order, stopping at first failure. Points which could not be inserted
+/// are kept for next replay. Spool is only locked while its file is read or rewritten, not while
+/// inserting, so fetch tasks can keep spooling meanwhile. Returns how many points were inserted.
+pub async fn replay(spool: &Mutex<Spool>, db: &DatabaseConnection) -> Result<usize, FetchError> {
+	let (mut lines, mut inserted) = (0, 0);
+	let mut error : Option<DbErr> = None;
+	loop {
+		let (read, chunk) = spool.lock().await.peek(lines, REPLAY_CHUNK)?;
+		if read == 0 {
+			break;
+		}
+		if !chunk.is_empty() {
+			if let Err(e) = entities::points::Entity::insert_many(
+				chunk.iter().map(|p| p.into()).collect::<Vec<entities::points::ActiveModel>>()
+			).exec(db).await {
+				error = Some(e);
+				break;
+			}
+		}
+		lines += read;
+		inserted += chunk.len();
+	}
+
+	let mut spool = spool.lock().await;
+	if lines > 0 {
+		spool.drain(lines)?;
+	}
+	match error {
+		Some(e) => {
+			spool.insert_failed();
+			if inserted == 0 { Err(e.into()) } else { Ok(inserted) }
+		},
+		None => {
+			spool.failed_at = None;
+			Ok(inserted)
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sea_orm::{ConnectionTrait, EntityTrait, QueryOrder};
+
+	use super::*;
+	use crate::data::{test_db, test_metric};
+
+	#[test]
+	fn file_name_follows_db_uri() {
+		let a = Spool::file_name("sqlite://a.db");
+		assert_eq!(a, Spool::file_name("sqlite://a.db"));
+		assert_ne!(a, Spool::file_name("sqlite://b.db"));
+		// must not change across builds, or spooled points would be forgotten
+		assert_eq!(Spool::file_name(""), "spool-cbf29ce484222325.jsonl");
+		assert!(!a.contains("a.db"));
+	}
+
+	fn spool(name: &str, capacity: usize) -> Spool {
+		let path = std::env::temp_dir().join(format!("dashboard-spool-{}-{}.jsonl", std::process::id(), name));
+		let _ = std::fs::remove_file(&path);
+		Spool::open(path, capacity).unwrap()
+	}
+
+	fn point(x: f64) -> SpooledPoint {
+		SpooledPoint { metric_id: 1, x, y: x * 2.0, flagged: false }
+	}
+
+	#[test]
+	fn push_until_full_and_reopen() {
+		let mut s = spool("full", 3);
+		for x in 0..3 {
+			s.push(point(x as f64)).unwrap();
+		}
+		assert!(s.is_full());
+		assert!(s.push(point(3.0)).is_err());
+		let reopened = Spool::open(s.path.clone(), 3).unwrap();
+		assert_eq!(reopened.depth(), 3);
+	}
+
+	#[tokio::test]
+	async fn replay_inserts_in_order_and_empties_spool() {
+		let db = test_db().await;
+		test_metric(&db, |_| {}).await;
+		let mut s = spool("replay", 1000);
+		for x in 0..250 {
+			s.push(point(x as f64)).unwrap();
+		}
+		let s = Mutex::new(s);
+		assert_eq!(replay(&s, &db).await.unwrap(), 250);
+		assert!(s.lock().await.is_empty());
+		let stored = entities::points::Entity::find()
+			.order_by_asc(entities::points::Column::Id)
+			.all(&db).await.unwrap();
+		let xs : Vec<f64> = stored.iter().map(|p| p.x).collect();
+		assert_eq!(xs, (0..250).map(|x| x as f64).collect::<Vec<f64>>());
+	}
+
+	#[tokio::test]
+	async fn replay_skips_broken_lines() {
+		let db = test_db().await;
+		test_metric(&db, |_| {}).await;
+		let mut s = spool("broken", 10);
+		s.push(point(1.0)).unwrap();
+		writeln!(OpenOptions::new().append(true).open(&s.path).unwrap(), "{{\"metric_id\":1,\"x\"").unwrap();
+		s.depth += 1;
+		s.push(point(2.0)).unwrap();
+		let s = Mutex::new(s);
+		assert_eq!(replay(&s, &db).await.unwrap(), 2);
+		assert!(s.lock().await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn failed_replay_keeps_points_and_backs_off() {
+		let db = test_db().await;
+		db.execute(db.get_database_backend().build(
+			&sea_orm::sea_query::Table::drop().table(entities::points::Entity).to_owned()
+		)).await.unwrap();
+		let mut s = spool("failed", 10);
+		s.push(point(1.0)).unwrap();
+		s.push(point(2.0)).unwrap();
+		let s = Mutex::new(s);
+		assert!(replay(&s, &db).await.is_err());
+		let s = s.lock().await;
+		assert_eq!(s.depth(), 2);
+		assert!(!s.should_replay());
+	}
+
+	#[test]
+	fn drain_keeps_points_pushed_meanwhile() {
+		let mut s = spool("drain", 10);
+		s.push(point(1.0)).unwrap();
+		s.push(point(2.0)).unwrap();
+		let (lines, _) = s.peek(0, 10).unwrap();
+		s.push(point(3.0)).unwrap();
+		s.drain(lines).unwrap();
+		assert_eq!(s.depth(), 1);
+		let (_, rest) = s.peek(0, 10).unwrap();
+		assert_eq!(rest[0].x, 3.0);
+	}
+}
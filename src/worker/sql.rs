@@ -0,0 +1,134 @@
+use serde_json::{Value, Map, Number};
+use sqlx::{Row, Column, TypeInfo, ValueRef, sqlite::{SqlitePool, SqliteRow}, postgres::{PgPool, PgRow}, types::Decimal};
+
+use crate::data::FetchError;
+
+/// Connection pool to a database read by a sql source
+#[derive(Clone)]
+pub enum SqlPool {
+	Sqlite(SqlitePool),
+	Postgres(PgPool),
+}
+
+fn number(v: Option<f64>) -> Value {
+	v.and_then(Number::from_f64).map(Value::Number).unwrap_or(Value::Null)
+}
+
+// sqlite columns from expressions have no declared type, so look at each value's own type
+fn sqlite_value(row: &SqliteRow, i: usize) -> Value {
+	let kind = match row.try_get_raw(i) {
+		Ok(v) if v.is_null() => return Value::Null,
+		Ok(v) => v.type_info().name().to_string(),
+		Err(_) => return Value::Null,
+	};
+	match kind.as_str() {
+		"INTEGER" => row.try_get::<i64, _>(i).map(Value::from).unwrap_or(Value::Null),
+		"REAL" => number(row.try_get::<f64, _>(i).ok()),
+		"BOOLEAN" => row.try_get::<bool, _>(i).map(Value::from).unwrap_or(Value::Null),
+		_ => row.try_get::<String, _>(i).map(Value::from).unwrap_or(Value::Null),
+	}
+}
+
+fn postgres_value(row: &PgRow, i: usize) -> Value {
+	let kind = row.columns()[i].type_info().name().to_string();
+	match kind.as_str() {
+		"INT2" => row.try_get::<Option<i16>, _>(i).ok().flatten().map(Value::from),
+		"INT4" => row.try_get::<Option<i32>, _>(i).ok().flatten().map(Value::from),
+		"INT8" => row.try_get::<Option<i64>, _>(i).ok().flatten().map(Value::from),
+		"FLOAT4" => row.try_get::<Option<f32>, _>(i).ok().flatten().map(|v| number(Some(v as f64))),
+		"FLOAT8" => row.try_get::<Option<f64>, _>(i).ok().flatten().map(|v| number(Some(v))),
+		"NUMERIC" => row.try_get::<Option<Decimal>, _>(i).ok().flatten().map(|v| number(v.to_string().parse().ok())),
+		"BOOL" => row.try_get::<Option<bool>, _>(i).ok().flatten().map(Value::from),
+		_ => row.try_get::<Option<String>, _>(i).ok().flatten().map(Value::from),
+	}.unwrap_or(Value::Null)
+}
+
+fn object<R: Row>(row: &R, value: impl Fn(&R, usize) -> Value) -> Value {
+	let mut map = Map::new();
+	for (i, col) in row.columns().iter().enumerate() {
+		map.insert(col.name().to_string(), value(row, i));
+	}
+	Value::Object(map)
+}
+
+impl SqlPool {
+	pub async fn connect(uri: &str) -> Result<Self, FetchError> {
+		if uri.starts_with("sqlite:") {
+			Ok(SqlPool::Sqlite(SqlitePool::connect(uri).await?))
+		} else if uri.starts_with("postgres:") || uri.starts_with("postgresql:") {
+			Ok(SqlPool::Postgres(PgPool::connect(uri).await?))
+		} else {
+			Err(FetchError::NotFound(format!("unsupported database '{}'", uri.split(':').next().unwrap_or(""))))
+		}
+	}
+
+	/// Run query and return rows as an array of objects keyed by column name. Query runs inside
+	/// a transaction which is always rolled back, so sources can't change the database.
+	pub async fn rows(&self, query: &str) -> Result<Value, FetchError> {
+		let rows = match self {
+			SqlPool::Sqlite(pool) => {
+				let mut tx = pool.begin().await?;
+				let rows = sqlx::query(query).fetch_all(&mut tx).await?;
+				tx.rollback().await?;
+				rows.iter().map(|r| object(r, sqlite_value)).collect()
+			},
+			SqlPool::Postgres(pool) => {
+				let mut tx = pool.begin().await?;
+				let rows = sqlx::query(query).fetch_all(&mut tx).await?;
+				tx.rollback().await?;
+				rows.iter().map(|r| object(r, postgres_value)).collect()
+			},
+		};
+		Ok(Value::Array(rows))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::data::{entities, test_db_uri, Payload};
+
+	async fn pool(name: &str) -> SqlPool {
+		let uri = test_db_uri(name);
+		let pool = SqlPool::connect(&uri).await.unwrap();
+		let SqlPool::Sqlite(sqlite) = &pool else { unreachable!() };
+		sqlx::query("CREATE TABLE jobs (id INTEGER PRIMARY KEY, state TEXT, cost REAL)").execute(sqlite).await.unwrap();
+		sqlx::query("INSERT INTO jobs (state, cost) VALUES ('queued', 1.5), ('queued', 2.0), ('done', 4.0)").execute(sqlite).await.unwrap();
+		pool
+	}
+
+	fn extract(rows: Value, query: &str) -> Result<Option<f64>, FetchError> {
+		let metric = entities::metrics::Model { query: query.into(), ..Default::default() };
+		metric.extract(&Payload::Json(rows))
+	}
+
+	#[tokio::test]
+	async fn scalar_results_are_read() {
+		let pool = pool("sql-scalar").await;
+		let rows = pool.rows("SELECT count(*) AS queued, sum(cost) AS cost FROM jobs WHERE state = 'queued'").await.unwrap();
+		assert_eq!(rows, serde_json::json!([{ "queued": 2, "cost": 3.5 }]));
+		assert_eq!(extract(rows.clone(), "[0].\"queued\"").unwrap(), Some(2.0));
+		assert_eq!(extract(rows, "[0].\"cost\"").unwrap(), Some(3.5));
+	}
+
+	#[tokio::test]
+	async fn non_numeric_or_empty_results_are_errors() {
+		let pool = pool("sql-errors").await;
+		let text = pool.rows("SELECT state FROM jobs WHERE id = 1").await.unwrap();
+		assert!(extract(text, "[0].\"state\"").is_err());
+		let empty = pool.rows("SELECT cost FROM jobs WHERE state = 'failed'").await.unwrap();
+		assert_eq!(empty, serde_json::json!([]));
+		assert!(extract(empty, "[0].\"cost\"").is_err());
+		assert!(pool.rows("SELECT nope FROM jobs").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn writes_are_rolled_back() {
+		let pool = pool("sql-rollback").await;
+		let inserted = pool.rows("INSERT INTO jobs (state, cost) VALUES ('sneaky', 9.0) RETURNING id").await.unwrap();
+		assert_eq!(inserted, serde_json::json!([{ "id": 4 }]));
+		pool.rows("DELETE FROM jobs").await.unwrap();
+		let count = pool.rows("SELECT count(*) AS n FROM jobs").await.unwrap();
+		assert_eq!(count, serde_json::json!([{ "n": 3 }]));
+	}
+}
@@ -1,101 +1,247 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::{Duration, Instant}, collections::HashMap};
 
 use chrono::Utc;
-use sea_orm::{DatabaseConnection, ActiveValue::NotSet, Set, EntityTrait};
-use tokio::sync::watch;
-use tracing::error;
+use sea_orm::{DatabaseConnection, ActiveValue::NotSet, Set, EntityTrait, QueryFilter, ColumnTrait, sea_query::Expr};
+use tokio::{sync::{watch, Mutex}, task::JoinSet};
+use tracing::{debug, error, info, warn};
 
 use crate::data::{entities, FetchError};
 
-async fn fetch(url: &str) -> Result<serde_json::Value, FetchError> {
-	Ok(reqwest::get(url).await?.json().await?)
+use super::{spool::{self, Spool, SpooledPoint}, telemetry::Telemetry, fetcher::{Fetcher, Polled}, chain};
+
+#[derive(Debug, Clone)]
+pub struct SurveyorConfig {
+	/// seconds between each activation
+	pub interval: i64,
+	/// seconds before sources and metrics are reloaded from db
+	pub cache_time: i64,
+	/// how long to wait for in-flight fetches when stopping
+	pub grace_period: Duration,
 }
 
 pub async fn surveyor_loop(
 	db: DatabaseConnection,
-	interval:i64,
-	cache_time:i64,
-	run: watch::Receiver<bool>,
+	config: SurveyorConfig,
+	mut run: watch::Receiver<bool>,
 	index: usize,
+	spool: Arc<Mutex<Spool>>,
+	telemetry: Arc<Telemetry>,
+	fetcher: Arc<Fetcher>,
 ) {
+	let SurveyorConfig { interval, cache_time, grace_period } = config;
 	let mut last_activation = Utc::now().timestamp();
 	let mut last_fetch = 0;
 	let mut sources = vec![];
 	let mut metrics = Arc::new(vec![]);
+	let mut tasks = JoinSet::new();
+	let mut pending = HashMap::new(); // task id -> source id, to not queue a source twice
+	let last_stored = Arc::new(std::sync::Mutex::new(HashMap::new())); // metric id -> last stored (x, y)
+	telemetry.tick(index); // alive from the start, not only after first activation
 
 	while *run.borrow() {
-		// sleep until next activation
+		// sleep until next activation, waking up early if asked to stop
 		let delta_time = (interval as i64) - (Utc::now().timestamp() - last_activation);
 		if delta_time > 0 {
-			tokio::time::sleep(std::time::Duration::from_secs(delta_time as u64)).await;
+			tokio::select! {
+				_ = tokio::time::sleep(Duration::from_secs(delta_time as u64)) => {},
+				_ = run.changed() => continue,
+			}
+		}
+		while let Some(res) = tasks.try_join_next_with_id() {
+			match res {
+				Ok((id, ())) => { pending.remove(&id); },
+				Err(e) => {
+					pending.remove(&e.id());
+					error!(target: "surveyor", "[{}] Fetch task failed: {:?}", index, e);
+				},
+			}
 		}
 		last_activation = Utc::now().timestamp();
+		telemetry.tick(index);
 
 		if Utc::now().timestamp() - last_fetch > cache_time {
 			// TODO do both concurrently
 			match entities::sources::Entity::find().all(&db).await {
-				Ok(srcs) => sources = srcs,
+				Ok(srcs) => {
+					sources = srcs;
+					telemetry.db_state(index, true);
+				},
 				Err(e) => {
+					telemetry.db_state(index, false);
+					// keep surveying cached sources, points will be spooled until db comes back
 					error!(target: "surveyor", "[{}] Could not fetch sources: {:?}", index, e);
-					continue;
 				}
 			}
 			match entities::metrics::Entity::find().all(&db).await {
 				Ok(mtrcs) => metrics = Arc::new(mtrcs),
-				Err(e) => {
-					error!(target: "surveyor", "[{}] Could not fetch metrics: {:?}", index, e);
-					continue;
+				Err(e) => error!(target: "surveyor", "[{}] Could not fetch metrics: {:?}", index, e),
+			}
+			for source in sources.iter().filter(|s| s.archive_days > 0) {
+				let cutoff = (Utc::now().timestamp() - source.archive_days as i64 * 86400) as f64;
+				match entities::payloads::Entity::delete_many()
+					.filter(entities::payloads::Column::SourceId.eq(source.id))
+					.filter(entities::payloads::Column::FetchedAt.lt(cutoff))
+					.exec(&db).await
+				{
+					Ok(res) if res.rows_affected > 0 => info!(target: "surveyor", "[{}] Removed {} archived payloads of {}", index, res.rows_affected, source.name),
+					Ok(_) => {},
+					Err(e) => warn!(target: "surveyor", "[{}] Could not prune archive of {}: {:?}", index, source.name, e),
 				}
 			}
 			last_fetch = Utc::now().timestamp();
 		}
 
+		if spool.lock().await.should_replay() {
+			match spool::replay(&spool, &db).await {
+				Ok(count) => info!(target: "surveyor", "[{}] Replayed {} spooled points, {} left in spool", index, count, spool.lock().await.depth()),
+				Err(e) => warn!(target: "surveyor", "[{}] Could not replay spool ({} points pending): {:?}", index, spool.lock().await.depth(), e),
+			}
+		}
+		telemetry.spool_depth(index, spool.lock().await.depth());
+
+		let mut lag = 0;
+		// chained sources look up their dependencies here
+		let sources_snapshot = Arc::new(sources.clone());
+
 		for source in sources.iter_mut() {
 			if !source.enabled || !source.ready() {
 				continue;
 			}
+			if pending.values().any(|id| *id == source.id) {
+				debug!(target: "surveyor", "[{}] Source {} is still queued, not fetching again", index, source.name);
+				continue;
+			}
+			if let Some(wait) = fetcher.backoff(source.id) {
+				debug!(target: "surveyor", "[{}] Source {} is backing off for {}s", index, source.name, wait);
+				continue;
+			}
 
 			let metrics_snapshot = metrics.clone();
 			let db_clone = db.clone();
 			let source_clone = source.clone();
+			let spool_clone = spool.clone();
+			let telemetry_clone = telemetry.clone();
+			let fetcher_clone = fetcher.clone();
+			let sources_clone = sources_snapshot.clone();
+			let last_stored_clone = last_stored.clone();
 			let now = Utc::now().timestamp();
+			lag = std::cmp::max(lag, -source.cooldown());
 			source.last_update = now; // TODO kinda meh
 			// we set this before knowing about fetch result, to avoid re-running a fetch
 			// next time this loop runs. But the task only sets last_update on db if fetch succeeds,
 			// so if an error happens the client and server last_update fields will differ until fetched
 			// again. This could be avoided by keeping track of which threads are trying which sources,
 			// but also only trying to fetch at certain intervals to stay aligned might be desirable.
-			tokio::spawn(async move {
-				match fetch(&source_clone.url).await {
-					Ok(res) => {
-						if let Err(e) = entities::sources::Entity::update(
-							entities::sources::ActiveModel{id: Set(source_clone.id), last_update: Set(now), ..Default::default()}
-						).exec(&db_clone).await {
-							error!(target: "surveyor", "[{}] Failed setting last_update ({:?}) for source {:?} but successfully fetched '{}', aborting", index, e, source_clone, res);
-							return;
-						}
+			let inflight = telemetry.task_started(index);
+			let handle = tasks.spawn(async move {
+				let _inflight = inflight;
+				telemetry_clone.fetch_attempted(index, source_clone.id, &source_clone.name);
+				let polled = match chain::resolve(&fetcher_clone, &source_clone, &sources_clone).await {
+					Ok(resolved) => fetcher_clone.poll(&resolved).await,
+					Err(e) => Err(e),
+				};
+				match polled {
+					Ok(Polled { payload: res, modified }) => {
+						let fetched_at = now;
 						let now = Utc::now().timestamp() as f64;
-						for metric in metrics_snapshot.iter().filter(|x| source_clone.id == x.source_id) {
+						// on 304 either store cached values again or just mark source as fetched
+						let record = modified || source_clone.record_unchanged;
+						let mut rejected = 0;
+						for metric in metrics_snapshot.iter().filter(|x| record && source_clone.id == x.source_id) {
 							match metric.extract(&res) {
 								// note that Err and None mean different things: Err for broken queries, None for
 								// missing values. Only first one is reported
-								Ok(value) => {
-									if let Some(v) = value {
-										if let Err(e) = entities::points::Entity::insert(
-											entities::points::ActiveModel {
-												id: NotSet, metric_id: Set(metric.id), x: Set(now), y: Set(v),
-										}).exec(&db_clone).await {
-											error!(target: "surveyor", "[{}] Could not insert record ({},{}) : {:?}", index, now, v, e);
+								Ok(Some(raw)) => {
+									let Some((v, flagged)) = metric.validate(raw) else {
+										rejected += 1;
+										telemetry_clone.value_rejected(index, metric.id, &metric.name);
+										warn!(target: "surveyor", "[{}] Rejected value {} for '{}' from {}", index, raw, metric.name, source_clone.name);
+										continue;
+									};
+									{
+										let mut last_stored = last_stored_clone.lock().unwrap_or_else(|e| e.into_inner());
+										if !metric.should_store(last_stored.get(&metric.id).copied(), now, v) {
+											continue; // within deadband
 										}
+										last_stored.insert(metric.id, (now, v));
 									}
+									let start = Instant::now();
+									let res = entities::points::Entity::insert(
+										entities::points::ActiveModel {
+											id: NotSet, metric_id: Set(metric.id), x: Set(now), y: Set(v), flagged: Set(flagged),
+									}).exec(&db_clone).await;
+									telemetry_clone.insert_took(index, start.elapsed().as_secs_f64());
+									telemetry_clone.db_state(index, res.is_ok());
+									if let Err(e) = res {
+										let mut spool = spool_clone.lock().await;
+										match spool.push(SpooledPoint { metric_id: metric.id, x: now, y: v, flagged }) {
+											Ok(()) => warn!(target: "surveyor", "[{}] Could not insert record ({},{}), spooled ({} pending) : {:?}", index, now, v, spool.depth(), e),
+											Err(se) => error!(target: "surveyor", "[{}] Could not insert record ({},{}) nor spool it ({:?}) : {:?}", index, now, v, se, e),
+										}
+										telemetry_clone.spool_depth(index, spool.depth());
+									}
+								},
+								Ok(None) => {},
+								Err(e) => {
+									telemetry_clone.extraction_failed(index, metric.id, &metric.name);
+									error!(target: "surveyor", "[{}] Failed extracting '{}' from {}: {:?}", index, metric.name, source_clone.name, e);
 								},
-								Err(e) => error!(target: "surveyor", "[{}] Failed extracting '{}' from {}: {:?}", index, metric.name, source_clone.name, e),
 							}
 						}
+						if rejected > 0 {
+							if let Err(e) = entities::sources::Entity::update_many()
+								.col_expr(entities::sources::Column::Rejected, Expr::col(entities::sources::Column::Rejected).add(rejected))
+								.filter(entities::sources::Column::Id.eq(source_clone.id))
+								.exec(&db_clone).await {
+								warn!(target: "surveyor", "[{}] Could not count {} rejected values for {}: {:?}", index, rejected, source_clone.name, e);
+							}
+						}
+						if record && source_clone.archive {
+							let archived = match entities::payloads::Model::pack(source_clone.id, now, &res) {
+								Ok(model) => entities::payloads::Entity::insert(model).exec(&db_clone).await.map(|_| ()).map_err(FetchError::from),
+								Err(e) => Err(e),
+							};
+							if let Err(e) = archived {
+								warn!(target: "surveyor", "[{}] Could not archive payload of {}: {:?}", index, source_clone.name, e);
+							}
+						}
+						// only bump last_update once points are stored: if this task gets cancelled
+						// midway, source will be fetched again
+						if let Err(e) = entities::sources::Entity::update(
+							entities::sources::ActiveModel{id: Set(source_clone.id), last_update: Set(fetched_at), ..Default::default()}
+						).exec(&db_clone).await {
+							telemetry_clone.db_state(index, false);
+							error!(target: "surveyor", "[{}] Failed setting last_update ({:?}) for source {} but successfully fetched '{}'", index, e, source_clone.name, res);
+						}
+					},
+					Err(FetchError::Throttled(wait)) => {
+						telemetry_clone.fetch_failed(index, source_clone.id);
+						warn!(target: "surveyor", "[{}] Source {} is rate limited, backing off for {}s", index, source_clone.name, wait);
+					},
+					Err(e) => {
+						telemetry_clone.fetch_failed(index, source_clone.id);
+						error!(target: "surveyor", "[{}] Failed fetching {}: {:?}", index, source_clone.name, e);
 					},
-					Err(e) => error!(target: "surveyor", "[{}] Failed fetching {}: {:?}", index, source_clone.name, e),
 				}
 			});
+			pending.insert(handle.id(), source.id);
+		}
+
+		telemetry.scheduler_lag(index, lag);
+	}
+
+	if !tasks.is_empty() {
+		info!(target: "surveyor", "[{}] Waiting up to {}s for {} fetch tasks to finish", index, grace_period.as_secs(), tasks.len());
+		let drain = async {
+			while let Some(res) = tasks.join_next().await {
+				if let Err(e) = res {
+					error!(target: "surveyor", "[{}] Fetch task failed: {:?}", index, e);
+				}
+			}
+		};
+		if tokio::time::timeout(grace_period, drain).await.is_err() {
+			warn!(target: "surveyor", "[{}] Grace period expired, aborting {} fetch tasks", index, tasks.len());
+			tasks.shutdown().await;
 		}
 	}
 }
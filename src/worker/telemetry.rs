@@ -0,0 +1,375 @@
+use std::{collections::BTreeMap, sync::{Arc, Mutex}, net::TcpListener, convert::Infallible, fmt::Write};
+
+use chrono::Utc;
+use hyper::{Body, Request, Response, Server, StatusCode, service::{make_service_fn, service_fn}};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+// upper bounds (in seconds) for insert latency histogram buckets
+const INSERT_BUCKETS : &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+	buckets: Vec<u64>,
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn observe(&mut self, value: f64) {
+		if self.buckets.is_empty() {
+			self.buckets = vec![0; INSERT_BUCKETS.len()];
+		}
+		for (i, bound) in INSERT_BUCKETS.iter().enumerate() {
+			if value <= *bound {
+				self.buckets[i] += 1;
+			}
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+#[derive(Default)]
+struct TelemetryData {
+	// keyed like counters, ids of different databases clash
+	source_names: BTreeMap<(usize, i64), String>,
+	metric_names: BTreeMap<(usize, i64), String>,
+	fetches: BTreeMap<(usize, i64), u64>,
+	fetch_failures: BTreeMap<(usize, i64), u64>,
+	extraction_errors: BTreeMap<(usize, i64), u64>,
+	rejected_values: BTreeMap<(usize, i64), u64>,
+	insert_latency: BTreeMap<usize, Histogram>,
+	scheduler_lag: BTreeMap<usize, i64>,
+	inflight: BTreeMap<usize, i64>,
+	db_up: BTreeMap<usize, bool>,
+	spool_depth: BTreeMap<usize, usize>,
+	last_tick: BTreeMap<usize, i64>,
+}
+
+/// Fetch task being counted as in flight. Tasks aborted at shutdown or panicking drop it too.
+pub struct InflightTask {
+	telemetry: Arc<Telemetry>,
+	db: usize,
+}
+
+impl Drop for InflightTask {
+	fn drop(&mut self) {
+		*self.telemetry.data().inflight.entry(self.db).or_insert(0) -= 1;
+	}
+}
+
+/// Counters and gauges describing what surveyors are doing, shared across all worker tasks
+#[derive(Default)]
+pub struct Telemetry {
+	data: Mutex<TelemetryData>,
+}
+
+impl Telemetry {
+	fn data(&self) -> std::sync::MutexGuard<'_, TelemetryData> {
+		self.data.lock().unwrap_or_else(|e| e.into_inner()) // counters are still good if a task panicked
+	}
+
+	pub fn fetch_attempted(&self, db: usize, source_id: i64, source_name: &str) {
+		let mut data = self.data();
+		data.source_names.insert((db, source_id), source_name.to_string());
+		*data.fetches.entry((db, source_id)).or_insert(0) += 1;
+		data.fetch_failures.entry((db, source_id)).or_insert(0);
+	}
+
+	pub fn fetch_failed(&self, db: usize, source_id: i64) {
+		*self.data().fetch_failures.entry((db, source_id)).or_insert(0) += 1;
+	}
+
+	pub fn extraction_failed(&self, db: usize, metric_id: i64, metric_name: &str) {
+		let mut data = self.data();
+		data.metric_names.insert((db, metric_id), metric_name.to_string());
+		*data.extraction_errors.entry((db, metric_id)).or_insert(0) += 1;
+	}
+
+	pub fn value_rejected(&self, db: usize, metric_id: i64, metric_name: &str) {
+		let mut data = self.data();
+		data.metric_names.insert((db, metric_id), metric_name.to_string());
+		*data.rejected_values.entry((db, metric_id)).or_insert(0) += 1;
+	}
+
+	pub fn insert_took(&self, db: usize, seconds: f64) {
+		self.data().insert_latency.entry(db).or_default().observe(seconds);
+	}
+
+	pub fn scheduler_lag(&self, db: usize, seconds: i64) {
+		self.data().scheduler_lag.insert(db, seconds);
+	}
+
+	/// Count a fetch task as in flight until returned guard is dropped
+	pub fn task_started(self: &Arc<Self>, db: usize) -> InflightTask {
+		*self.data().inflight.entry(db).or_insert(0) += 1;
+		InflightTask { telemetry: self.clone(), db }
+	}
+
+	pub fn db_state(&self, db: usize, up: bool) {
+		self.data().db_up.insert(db, up);
+	}
+
+	pub fn spool_depth(&self, db: usize, depth: usize) {
+		self.data().spool_depth.insert(db, depth);
+	}
+
+	pub fn tick(&self, db: usize) {
+		self.data().last_tick.insert(db, Utc::now().timestamp());
+	}
+
+	/// Report connectivity and liveness of each surveyor as json. Returns also if all surveyors
+	/// ticked in the last `timeout` seconds and if all `databases` are connected.
+	pub fn health(&self, databases: usize, timeout: i64) -> (bool, bool, serde_json::Value) {
+		let data = self.data();
+		let now = Utc::now().timestamp();
+		let mut alive = true;
+		let mut ready = true;
+		let mut report = vec![];
+		for db in 0..databases {
+			// None until first connection attempt, which may take a while with migrations
+			let db_up = data.db_up.get(&db).copied();
+			let connected = db_up.unwrap_or(false);
+			let last_tick = data.last_tick.get(&db).copied();
+			let ticking = match last_tick {
+				Some(t) => now - t <= timeout,
+				None => false,
+			};
+			let starting = db_up.is_none() && last_tick.is_none();
+			// a surveyor ticks even while its db is down, stopping means it's stuck
+			alive &= ticking || starting;
+			ready &= connected && ticking;
+			report.push(serde_json::json!({
+				"db": db,
+				"connected": db_up,
+				"ticking": ticking,
+				"last_tick": last_tick,
+				"spool_depth": data.spool_depth.get(&db).copied().unwrap_or(0),
+			}));
+		}
+		(alive, ready, serde_json::json!({ "alive": alive, "ready": ready, "databases": report }))
+	}
+
+	/// Render all values in Prometheus text exposition format
+	pub fn render(&self) -> String {
+		let data = self.data();
+		let mut out = String::new();
+		let source = |key: &(usize, i64)| escape(data.source_names.get(key).map(|s| s.as_str()).unwrap_or(""));
+		let metric = |key: &(usize, i64)| escape(data.metric_names.get(key).map(|s| s.as_str()).unwrap_or(""));
+
+		header(&mut out, "dashboard_fetches_total", "counter", "Fetches attempted per source");
+		for (key @ (db, id), v) in data.fetches.iter() {
+			let _ = writeln!(out, "dashboard_fetches_total{{db=\"{}\",source_id=\"{}\",source=\"{}\"}} {}", db, id, source(key), v);
+		}
+		header(&mut out, "dashboard_fetch_failures_total", "counter", "Failed fetches per source");
+		for (key @ (db, id), v) in data.fetch_failures.iter() {
+			let _ = writeln!(out, "dashboard_fetch_failures_total{{db=\"{}\",source_id=\"{}\",source=\"{}\"}} {}", db, id, source(key), v);
+		}
+		header(&mut out, "dashboard_extraction_errors_total", "counter", "Failed query extractions per metric");
+		for (key @ (db, id), v) in data.extraction_errors.iter() {
+			let _ = writeln!(out, "dashboard_extraction_errors_total{{db=\"{}\",metric_id=\"{}\",metric=\"{}\"}} {}", db, id, metric(key), v);
+		}
+		header(&mut out, "dashboard_rejected_values_total", "counter", "Values rejected at ingest per metric");
+		for (key @ (db, id), v) in data.rejected_values.iter() {
+			let _ = writeln!(out, "dashboard_rejected_values_total{{db=\"{}\",metric_id=\"{}\",metric=\"{}\"}} {}", db, id, metric(key), v);
+		}
+		header(&mut out, "dashboard_insert_duration_seconds", "histogram", "Time taken inserting a point");
+		for (db, h) in data.insert_latency.iter() {
+			for (bound, count) in INSERT_BUCKETS.iter().zip(h.buckets.iter()) {
+				let _ = writeln!(out, "dashboard_insert_duration_seconds_bucket{{db=\"{}\",le=\"{}\"}} {}", db, bound, count);
+			}
+			let _ = writeln!(out, "dashboard_insert_duration_seconds_bucket{{db=\"{}\",le=\"+Inf\"}} {}", db, h.count);
+			let _ = writeln!(out, "dashboard_insert_duration_seconds_sum{{db=\"{}\"}} {}", db, h.sum);
+			let _ = writeln!(out, "dashboard_insert_duration_seconds_count{{db=\"{}\"}} {}", db, h.count);
+		}
+		header(&mut out, "dashboard_scheduler_lag_seconds", "gauge", "How late the most overdue source was fetched in last activation");
+		for (db, v) in data.scheduler_lag.iter() {
+			let _ = writeln!(out, "dashboard_scheduler_lag_seconds{{db=\"{}\"}} {}", db, v);
+		}
+		header(&mut out, "dashboard_inflight_tasks", "gauge", "Fetch tasks currently running");
+		for (db, v) in data.inflight.iter() {
+			let _ = writeln!(out, "dashboard_inflight_tasks{{db=\"{}\"}} {}", db, v);
+		}
+		header(&mut out, "dashboard_db_up", "gauge", "Whether last operation on database succeeded");
+		for (db, v) in data.db_up.iter() {
+			let _ = writeln!(out, "dashboard_db_up{{db=\"{}\"}} {}", db, *v as u8);
+		}
+		header(&mut out, "dashboard_spool_depth", "gauge", "Points waiting in spool to be inserted");
+		for (db, v) in data.spool_depth.iter() {
+			let _ = writeln!(out, "dashboard_spool_depth{{db=\"{}\"}} {}", db, v);
+		}
+		out
+	}
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+	label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn health_response(ok: bool, report: serde_json::Value) -> Result<Response<Body>, hyper::http::Error> {
+	Response::builder()
+		.status(if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+		.header("Content-Type", "application/json")
+		.body(Body::from(report.to_string()))
+}
+
+fn route(req: Request<Body>, telemetry: &Telemetry, databases: usize, timeout: i64) -> Response<Body> {
+	match req.uri().path() {
+		"/metrics" => Response::builder()
+			.header("Content-Type", "text/plain; version=0.0.4")
+			.body(Body::from(telemetry.render())),
+		"/healthz" => {
+			let (alive, _ready, report) = telemetry.health(databases, timeout);
+			health_response(alive, report)
+		},
+		"/readyz" => {
+			let (_alive, ready, report) = telemetry.health(databases, timeout);
+			health_response(ready, report)
+		},
+		_ => Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body(Body::from("not found\n")),
+	}.unwrap_or_default()
+}
+
+/// Serve `/metrics`, `/healthz` and `/readyz`. Surveyors are considered stuck if they don't tick
+/// for `timeout` seconds, and worker is ready only once all `databases` are connected.
+pub async fn telemetry_server(
+	listener: TcpListener,
+	telemetry: Arc<Telemetry>,
+	databases: usize,
+	timeout: i64,
+	mut run: watch::Receiver<bool>,
+) {
+	let make_svc = make_service_fn(move |_conn| {
+		let telemetry = telemetry.clone();
+		async move {
+			Ok::<_, Infallible>(service_fn(move |req| {
+				let res = route(req, &telemetry, databases, timeout);
+				async move { Ok::<_, Infallible>(res) }
+			}))
+		}
+	});
+
+	let addr = listener.local_addr().map(|a| a.to_string()).unwrap_or_default();
+	let server = match Server::from_tcp(listener) {
+		Ok(s) => s,
+		Err(e) => {
+			error!(target: "telemetry", "Could not serve on {}: {:?}", addr, e);
+			return;
+		}
+	};
+
+	info!(target: "telemetry", "Serving worker telemetry on http://{}/metrics", addr);
+
+	let res = server
+		.serve(make_svc)
+		.with_graceful_shutdown(async move {
+			while *run.borrow() {
+				if run.changed().await.is_err() { break; }
+			}
+		})
+		.await;
+
+	if let Err(e) = res {
+		error!(target: "telemetry", "HTTP server stopped: {:?}", e);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn starting_surveyor_is_alive_but_not_ready() {
+		let telemetry = Telemetry::default();
+		let (alive, ready, _) = telemetry.health(1, 30);
+		assert!(alive);
+		assert!(!ready);
+	}
+
+	#[test]
+	fn stuck_surveyor_is_dead_even_with_db_down() {
+		let telemetry = Telemetry::default();
+		telemetry.db_state(0, false);
+		telemetry.data().last_tick.insert(0, Utc::now().timestamp() - 60);
+		let (alive, _, _) = telemetry.health(1, 30);
+		assert!(!alive);
+		telemetry.tick(0);
+		let (alive, ready, _) = telemetry.health(1, 30);
+		assert!(alive);
+		assert!(!ready);
+	}
+
+	#[test]
+	fn dropped_tasks_leave_inflight_gauge() {
+		let telemetry = Arc::new(Telemetry::default());
+		let a = telemetry.task_started(0);
+		let b = telemetry.task_started(0);
+		assert_eq!(telemetry.data().inflight[&0], 2);
+		drop(a);
+		drop(b);
+		assert_eq!(telemetry.data().inflight[&0], 0);
+	}
+
+	#[test]
+	fn render_labels_series_with_names_of_their_database() {
+		let telemetry = Telemetry::default();
+		telemetry.fetch_attempted(0, 1, "alpha");
+		telemetry.fetch_attempted(1, 1, "be\"ta");
+		telemetry.fetch_failed(1, 1);
+		telemetry.value_rejected(0, 5, "temp");
+		telemetry.value_rejected(1, 5, "hum");
+		telemetry.insert_took(0, 0.02);
+		telemetry.scheduler_lag(0, 4);
+		telemetry.db_state(0, true);
+		telemetry.spool_depth(0, 3);
+		let expected = r#"# HELP dashboard_fetches_total Fetches attempted per source
+# TYPE dashboard_fetches_total counter
+dashboard_fetches_total{db="0",source_id="1",source="alpha"} 1
+dashboard_fetches_total{db="1",source_id="1",source="be\"ta"} 1
+# HELP dashboard_fetch_failures_total Failed fetches per source
+# TYPE dashboard_fetch_failures_total counter
+dashboard_fetch_failures_total{db="0",source_id="1",source="alpha"} 0
+dashboard_fetch_failures_total{db="1",source_id="1",source="be\"ta"} 1
+# HELP dashboard_extraction_errors_total Failed query extractions per metric
+# TYPE dashboard_extraction_errors_total counter
+# HELP dashboard_rejected_values_total Values rejected at ingest per metric
+# TYPE dashboard_rejected_values_total counter
+dashboard_rejected_values_total{db="0",metric_id="5",metric="temp"} 1
+dashboard_rejected_values_total{db="1",metric_id="5",metric="hum"} 1
+# HELP dashboard_insert_duration_seconds Time taken inserting a point
+# TYPE dashboard_insert_duration_seconds histogram
+dashboard_insert_duration_seconds_bucket{db="0",le="0.001"} 0
+dashboard_insert_duration_seconds_bucket{db="0",le="0.005"} 0
+dashboard_insert_duration_seconds_bucket{db="0",le="0.01"} 0
+dashboard_insert_duration_seconds_bucket{db="0",le="0.05"} 1
+dashboard_insert_duration_seconds_bucket{db="0",le="0.1"} 1
+dashboard_insert_duration_seconds_bucket{db="0",le="0.5"} 1
+dashboard_insert_duration_seconds_bucket{db="0",le="1"} 1
+dashboard_insert_duration_seconds_bucket{db="0",le="5"} 1
+dashboard_insert_duration_seconds_bucket{db="0",le="+Inf"} 1
+dashboard_insert_duration_seconds_sum{db="0"} 0.02
+dashboard_insert_duration_seconds_count{db="0"} 1
+# HELP dashboard_scheduler_lag_seconds How late the most overdue source was fetched in last activation
+# TYPE dashboard_scheduler_lag_seconds gauge
+dashboard_scheduler_lag_seconds{db="0"} 4
+# HELP dashboard_inflight_tasks Fetch tasks currently running
+# TYPE dashboard_inflight_tasks gauge
+# HELP dashboard_db_up Whether last operation on database succeeded
+# TYPE dashboard_db_up gauge
+dashboard_db_up{db="0"} 1
+# HELP dashboard_spool_depth Points waiting in spool to be inserted
+# TYPE dashboard_spool_depth gauge
+dashboard_spool_depth{db="0"} 3
+"#;
+		assert_eq!(telemetry.render(), expected);
+	}
+}
+
@@ -0,0 +1,233 @@
+use std::{collections::HashSet, error::Error, path::PathBuf};
+
+use eframe::egui::plot::PlotPoint;
+use sea_orm::{DatabaseConnection, EntityTrait, QueryFilter, QuerySelect, ColumnTrait, Condition, QueryOrder, Order, ActiveValue::NotSet, Set, TransactionTrait, FromQueryResult};
+use tracing::debug;
+
+use crate::{data::entities, util::{serialize_values, deserialize_values}};
+
+use super::rollup::DAY;
+
+pub type TransferError = Box<dyn Error + Send + Sync>;
+
+// how many imported points are inserted at once
+const IMPORT_BATCH : usize = 200;
+
+/// CSV columns holding x and y of each point. Defaults match files written by `export`.
+#[derive(Debug, Clone, Copy)]
+pub struct Columns {
+	pub x: usize,
+	pub y: usize,
+}
+
+impl Default for Columns {
+	fn default() -> Self {
+		Columns { x: 1, y: 2 }
+	}
+}
+
+/// Write points of metric between from and to to a CSV file, rollups included and flagged points
+/// left out, like they're plotted. Returns how many points were written.
+pub async fn export(
+	db: &DatabaseConnection,
+	metric: &entities::metrics::Model,
+	from: Option<f64>,
+	to: Option<f64>,
+	path: PathBuf,
+) -> Result<usize, TransferError> {
+	let mut points_filter = Condition::all()
+		.add(entities::points::Column::MetricId.eq(metric.id))
+		.add(entities::points::Column::Flagged.eq(false));
+	let mut rollups_filter = Condition::all()
+		.add(entities::rollups::Column::MetricId.eq(metric.id));
+	if let Some(from) = from {
+		points_filter = points_filter.add(entities::points::Column::X.gte(from));
+		rollups_filter = rollups_filter.add(entities::rollups::Column::X.gte(from));
+	}
+	if let Some(to) = to {
+		points_filter = points_filter.add(entities::points::Column::X.lte(to));
+		rollups_filter = rollups_filter.add(entities::rollups::Column::X.lte(to));
+	}
+	let mut points = entities::points::Entity::find()
+		.filter(points_filter)
+		.order_by(entities::points::Column::X, Order::Asc)
+		.all(db).await?;
+	let rollups = entities::rollups::Entity::find()
+		.filter(rollups_filter)
+		.all(db).await?;
+	if !rollups.is_empty() {
+		points.extend(rollups.into_iter().map(entities::points::Model::from));
+		points.sort_by(|a, b| a.x.total_cmp(&b.x));
+	}
+	let values : Vec<PlotPoint> = points.iter().map(|p| PlotPoint { x: p.x, y: p.y }).collect();
+	serialize_values(&values, metric, path)?;
+	Ok(values.len())
+}
+
+/// What importing a CSV file into a metric did, or would do
+#[derive(Debug, Default)]
+pub struct ImportReport {
+	/// records found in file
+	pub read: usize,
+	/// points stored, out of range ones included
+	pub inserted: usize,
+	/// points stored flagged, hidden from plots
+	pub flagged: usize,
+	/// values already stored at same x or rolled up, left alone
+	pub skipped: usize,
+	/// malformed records and values dropped by metric validation
+	pub rejected: usize,
+	/// why each malformed record couldn't be read
+	pub malformed: Vec<String>,
+}
+
+#[derive(FromQueryResult)]
+struct StoredX {
+	x: f64,
+}
+
+/// Read points from a CSV file into metric, validating values like fetched ones. Points at an x
+/// metric already has are skipped, so importing a file twice stores it once. With dry_run file
+/// is only checked and nothing is stored.
+pub async fn import(
+	db: &DatabaseConnection,
+	metric: &entities::metrics::Model,
+	path: PathBuf,
+	columns: Columns,
+	dry_run: bool,
+) -> Result<ImportReport, TransferError> {
+	let (name, _, _, records) = deserialize_values(path, columns.x, columns.y)?;
+	debug!(target: "transfer", "Importing {} records exported from '{}' into '{}'", records.len(), name, metric.name);
+	let mut report = ImportReport { read: records.len(), ..Default::default() };
+	let mut values = vec![];
+	for record in records {
+		match record {
+			Ok(v) => values.push(v),
+			Err(e) => report.malformed.push(e),
+		}
+	}
+	report.rejected = report.malformed.len();
+
+	// x of points already stored, records repeated in file are caught here too. Rolled up
+	// buckets hold their points already, exported ones come back at bucket center.
+	let mut seen : HashSet<u64> = HashSet::new();
+	let mut rolled : Vec<(f64, f64)> = vec![];
+	let min = values.iter().map(|v| v.x).fold(f64::INFINITY, f64::min);
+	let max = values.iter().map(|v| v.x).fold(f64::NEG_INFINITY, f64::max);
+	if min <= max {
+		let stored = entities::points::Entity::find()
+			.select_only()
+			.column(entities::points::Column::X)
+			.filter(entities::points::Column::MetricId.eq(metric.id))
+			.filter(entities::points::Column::X.between(min, max))
+			.into_model::<StoredX>()
+			.all(db).await?;
+		seen.extend(stored.into_iter().map(|p| p.x.to_bits()));
+		rolled = entities::rollups::Entity::find()
+			.filter(entities::rollups::Column::MetricId.eq(metric.id))
+			.filter(entities::rollups::Column::X.between(min - DAY as f64, max))
+			.order_by(entities::rollups::Column::X, Order::Asc)
+			.all(db).await?
+			.into_iter()
+			.map(|r| (r.x, r.x + r.resolution as f64))
+			.collect();
+	}
+
+	let mut points = vec![];
+	for v in values {
+		// buckets don't overlap, so the one starting last before x is the only candidate
+		let bucket = rolled.partition_point(|(start, _)| *start <= v.x);
+		let in_rollup = bucket > 0 && v.x < rolled[bucket - 1].1;
+		if in_rollup || !seen.insert(v.x.to_bits()) {
+			report.skipped += 1;
+			continue;
+		}
+		match metric.validate(v.y) {
+			Some((y, flagged)) => {
+				if flagged {
+					report.flagged += 1;
+				}
+				points.push(entities::points::ActiveModel {
+					id: NotSet, metric_id: Set(metric.id), x: Set(v.x), y: Set(y), flagged: Set(flagged),
+				});
+			},
+			None => report.rejected += 1,
+		}
+	}
+	report.inserted = points.len();
+	if !dry_run && !points.is_empty() {
+		// all or nothing, a broken import would otherwise be hard to tell apart from good points
+		let txn = db.begin().await?;
+		while !points.is_empty() {
+			let batch : Vec<_> = points.drain(..points.len().min(IMPORT_BATCH)).collect();
+			entities::points::Entity::insert_many(batch).exec(&txn).await?;
+		}
+		txn.commit().await?;
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sea_orm::PaginatorTrait;
+	use crate::data::{test_db, test_metric};
+
+	fn csv_file(name: &str, content: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("dashboard-transfer-{}-{}.csv", name, std::process::id()));
+		std::fs::write(&path, content).unwrap();
+		path
+	}
+
+	#[tokio::test]
+	async fn malformed_records_are_rejected_not_fatal() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |m| m.valid_max = Some(100.0)).await;
+		let path = csv_file("malformed", "metric,x,y\n,1,10\n,2,abc\n,3\n,4,1000\n,5,50\n");
+		let report = import(&db, &metric, path.clone(), Columns::default(), true).await.unwrap();
+		assert_eq!((report.read, report.inserted, report.rejected), (5, 2, 3));
+		assert_eq!(report.malformed.len(), 2);
+		assert!(report.malformed[0].contains("abc"), "{:?}", report.malformed);
+		assert_eq!(entities::points::Entity::find().count(&db).await.unwrap(), 0);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn importing_twice_stores_points_once() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |_| {}).await;
+		let path = csv_file("twice", "metric,x,y\n,1,10\n,2,20\n,2,21\n");
+		let first = import(&db, &metric, path.clone(), Columns::default(), false).await.unwrap();
+		assert_eq!((first.inserted, first.skipped), (2, 1));
+		let second = import(&db, &metric, path.clone(), Columns::default(), false).await.unwrap();
+		assert_eq!((second.inserted, second.skipped), (0, 3));
+		assert_eq!(entities::points::Entity::find().count(&db).await.unwrap(), 2);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn exported_points_import_back() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |_| {}).await;
+		let points : Vec<_> = [(1.0, 10.0, false), (2.0, 20.0, true), (3.0, 30.0, false)].into_iter()
+			.map(|(x, y, flagged)| entities::points::ActiveModel {
+				id: NotSet, metric_id: Set(metric.id), x: Set(x), y: Set(y), flagged: Set(flagged),
+			})
+			.collect();
+		entities::points::Entity::insert_many(points).exec(&db).await.unwrap();
+		entities::rollups::Entity::insert(entities::rollups::ActiveModel {
+			id: NotSet, metric_id: Set(metric.id), resolution: Set(60), x: Set(-60.0), min: Set(1.0), max: Set(3.0), avg: Set(2.0), count: Set(5),
+		}).exec(&db).await.unwrap();
+		let path = csv_file("export", "");
+		assert_eq!(export(&db, &metric, None, None, path.clone()).await.unwrap(), 3);
+		let report = import(&db, &metric, path.clone(), Columns::default(), true).await.unwrap();
+		assert_eq!((report.read, report.skipped, report.inserted), (3, 3, 0));
+		// 1.5 is new, -30 falls in the rolled up bucket and 3 is stored already
+		let path_in = csv_file("import", "metric,x,y\n,1.5,15\n,-30,2\n,3,30\n");
+		let report = import(&db, &metric, path_in.clone(), Columns::default(), true).await.unwrap();
+		assert_eq!((report.read, report.skipped, report.inserted), (3, 2, 1));
+		std::fs::remove_file(path_in).unwrap();
+		std::fs::remove_file(path).unwrap();
+	}
+}
+
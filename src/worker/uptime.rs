@@ -0,0 +1,235 @@
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDateTime, Utc};
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, native_tls};
+
+use crate::data::FetchError;
+
+// how long to wait for a connection or a response before considering target down
+const CHECK_TIMEOUT : Duration = Duration::from_secs(10);
+
+/// Read one DER element, returning its tag, its content and what follows it
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let (&tag, input) = input.split_first()?;
+	let (&len, mut input) = input.split_first()?;
+	let len = if len < 0x80 {
+		len as usize
+	} else {
+		let n = (len & 0x7f) as usize;
+		if n > 4 || input.len() < n { return None; }
+		let (bytes, rest) = input.split_at(n);
+		input = rest;
+		bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize)
+	};
+	if input.len() < len { return None; }
+	let (content, rest) = input.split_at(len);
+	Some((tag, content, rest))
+}
+
+/// Expiry of a DER encoded certificate, as unix timestamp
+fn not_after(cert: &[u8]) -> Option<i64> {
+	let (_, cert, _) = der(cert)?;
+	let (_, mut tbs, _) = der(cert)?;
+	if tbs.first() == Some(&0xa0) { // explicit version
+		tbs = der(tbs)?.2;
+	}
+	let (_, _, tbs) = der(tbs)?; // serial
+	let (_, _, tbs) = der(tbs)?; // signature algorithm
+	let (_, _, tbs) = der(tbs)?; // issuer
+	let (_, validity, _) = der(tbs)?;
+	let (_, _, validity) = der(validity)?; // not before
+	let (tag, time, _) = der(validity)?;
+	asn1_time(tag, time)
+}
+
+/// UTCTime or GeneralizedTime as unix timestamp
+fn asn1_time(tag: u8, time: &[u8]) -> Option<i64> {
+	let time = std::str::from_utf8(time).ok()?;
+	let time = match tag {
+		0x17 => { // UTCTime, two digits year
+			let year = time.get(..2).filter(|y| y.bytes().all(|b| b.is_ascii_digit()))?;
+			format!("{}{}", if year >= "50" { "19" } else { "20" }, time)
+		},
+		0x18 => time.to_string(), // GeneralizedTime
+		_ => return None,
+	};
+	Some(NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok()?.and_utc().timestamp())
+}
+
+/// Days until certificate presented by host expires, negative if already expired
+async fn cert_days(host: &str, port: u16) -> Result<f64, FetchError> {
+	let stream = TcpStream::connect((host, port)).await?;
+	// accept anything: we want to report expiry even of broken certificates
+	let connector = TlsConnector::from(
+		native_tls::TlsConnector::builder()
+			.danger_accept_invalid_certs(true)
+			.danger_accept_invalid_hostnames(true)
+			.build()
+			.map_err(std::io::Error::other)?
+	);
+	let tls = connector.connect(host, stream).await.map_err(std::io::Error::other)?;
+	let cert = tls.get_ref().peer_certificate().map_err(std::io::Error::other)?
+		.ok_or_else(|| std::io::Error::other("no certificate presented"))?;
+	let expiry = not_after(&cert.to_der().map_err(std::io::Error::other)?)
+		.ok_or_else(|| std::io::Error::other("could not read certificate expiry"))?;
+	Ok((expiry - Utc::now().timestamp()) as f64 / 86400.0)
+}
+
+async fn timed<T>(fut: impl std::future::Future<Output = Result<T, FetchError>>) -> Result<(T, f64), FetchError> {
+	let start = Instant::now();
+	match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+		Ok(res) => Ok((res?, start.elapsed().as_secs_f64())),
+		Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "check timed out").into()),
+	}
+}
+
+/// Check if target is reachable: `tcp://host:port` measures connect time, `tls://host[:port]` time
+/// to complete a TLS handshake (port defaults to 443), `http(s)://` urls measure time to first byte and report status. Payload is
+/// `{ "up": 1|0, "latency": seconds, "status": code, "cert_days": days }` with fields not
+/// applicable left out. A down target is not an error: it produces `"up": 0`.
+pub async fn check(client: &Client, target: &str) -> Result<Value, FetchError> {
+	let url = Url::parse(target).map_err(|e| FetchError::NotFound(format!("invalid target '{}': {}", target, e)))?;
+	let host = url.host_str().unwrap_or("").to_string();
+	let port = match (url.port_or_known_default(), url.scheme()) {
+		(Some(port), _) => port,
+		(None, "tcp") => return Err(FetchError::NotFound(format!("invalid target '{}': tcp needs a port", target))),
+		(None, _) => 443,
+	};
+	let mut payload = json!({ "up": 0 });
+
+	let res = match url.scheme() {
+		"tcp" => timed(async {
+			Ok(TcpStream::connect((host.as_str(), port)).await?)
+		}).await.map(|(_, latency)| {
+			payload["up"] = json!(1);
+			payload["latency"] = json!(latency);
+		}),
+		"tls" => timed(cert_days(&host, port)).await.map(|(days, latency)| {
+			payload["up"] = json!(1);
+			payload["latency"] = json!(latency);
+			payload["cert_days"] = json!(days);
+		}),
+		"http" | "https" => timed(async {
+			Ok(client.get(url.clone()).send().await?.status())
+		}).await.map(|(status, latency)| {
+			payload["up"] = json!(if status.is_client_error() || status.is_server_error() { 0 } else { 1 });
+			payload["status"] = json!(status.as_u16());
+			payload["latency"] = json!(latency);
+		}),
+		scheme => return Err(FetchError::NotFound(format!("unsupported scheme '{}', use tcp, tls, http or https", scheme))),
+	};
+	if let Err(e) = res {
+		payload["error"] = json!(format!("{:?}", e));
+	}
+
+	if url.scheme() == "https" {
+		if let Ok(Ok(days)) = tokio::time::timeout(CHECK_TIMEOUT, cert_days(&host, port)).await {
+			payload["cert_days"] = json!(days);
+		}
+	}
+
+	Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::net::TcpListener;
+	use tokio_native_tls::TlsAcceptor;
+
+	use super::*;
+
+	const LOCALHOST_CRT : &str = include_str!("../../testdata/uptime/localhost.crt");
+	const LOCALHOST_KEY : &str = include_str!("../../testdata/uptime/localhost.key");
+	const FAR_CRT : &str = include_str!("../../testdata/uptime/far.crt");
+
+	fn der_of(pem: &str) -> Vec<u8> {
+		native_tls::Certificate::from_pem(pem.as_bytes()).unwrap().to_der().unwrap()
+	}
+
+	#[test]
+	fn not_after_reads_utc_time() {
+		assert_eq!(not_after(&der_of(LOCALHOST_CRT)), Some(2107745767)); // 2036-10-16 04:56:07
+	}
+
+	#[test]
+	fn not_after_reads_generalized_time() {
+		assert_eq!(not_after(&der_of(FAR_CRT)), Some(4945985767)); // 2126-09-25 04:56:07
+	}
+
+	#[test]
+	fn not_after_rejects_garbage() {
+		assert_eq!(not_after(&[]), None);
+		assert_eq!(not_after(&[0x30, 0x82, 0xff]), None);
+		let der = der_of(LOCALHOST_CRT);
+		assert_eq!(not_after(&der[..der.len() / 3]), None);
+	}
+
+	#[test]
+	fn asn1_time_rejects_malformed_years() {
+		assert_eq!(asn1_time(0x17, b"361016045607Z"), Some(2107745767));
+		assert_eq!(asn1_time(0x17, b"991231235959Z"), Some(946684799));
+		assert_eq!(asn1_time(0x17, "1\u{e9}1016045607Z".as_bytes()), None); // would split a char
+		assert_eq!(asn1_time(0x17, b"+11016045607Z"), None);
+		assert_eq!(asn1_time(0x17, b"3"), None);
+		assert_eq!(asn1_time(0x17, b""), None);
+		assert_eq!(asn1_time(0x18, b"20361016045607Z"), Some(2107745767));
+		assert_eq!(asn1_time(0x02, b"20361016045607Z"), None);
+	}
+
+	#[tokio::test]
+	async fn tcp_check_against_local_listener() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let payload = check(&Client::new(), &format!("tcp://127.0.0.1:{}", port)).await.unwrap();
+		assert_eq!(payload["up"], 1);
+		assert!(payload["latency"].as_f64().unwrap() >= 0.0);
+		assert!(payload.get("cert_days").is_none());
+
+		drop(listener);
+		let payload = check(&Client::new(), &format!("tcp://127.0.0.1:{}", port)).await.unwrap();
+		assert_eq!(payload["up"], 0);
+		assert!(payload.get("error").is_some());
+	}
+
+	#[tokio::test]
+	async fn tcp_target_needs_a_port() {
+		assert!(matches!(check(&Client::new(), "tcp://127.0.0.1").await, Err(FetchError::NotFound(_))));
+		assert!(matches!(check(&Client::new(), "tcp://127.0.0.1:").await, Err(FetchError::NotFound(_))));
+	}
+
+	#[tokio::test]
+	async fn tls_check_against_local_listener() {
+		let identity = native_tls::Identity::from_pkcs8(LOCALHOST_CRT.as_bytes(), LOCALHOST_KEY.as_bytes()).unwrap();
+		let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let _ = acceptor.accept(stream).await;
+			}
+		});
+
+		let payload = check(&Client::new(), &format!("tls://127.0.0.1:{}", port)).await.unwrap();
+		assert_eq!(payload["up"], 1);
+		assert!(payload["latency"].as_f64().unwrap() > 0.0);
+		let expected = (2107745767 - Utc::now().timestamp()) as f64 / 86400.0;
+		assert!((payload["cert_days"].as_f64().unwrap() - expected).abs() < 0.01);
+	}
+
+	#[tokio::test]
+	async fn tls_check_of_plain_tcp_is_down() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				drop(stream);
+			}
+		});
+		let payload = check(&Client::new(), &format!("tls://127.0.0.1:{}", port)).await.unwrap();
+		assert_eq!(payload["up"], 0);
+		assert!(payload.get("cert_days").is_none());
+	}
+}
@@ -1,10 +1,13 @@
 use chrono::Utc;
-use sea_orm::{TransactionTrait, DatabaseConnection, EntityTrait, Condition, ColumnTrait, QueryFilter, Set, QueryOrder, Order, ActiveModelTrait, ActiveValue::{NotSet, self}, Database, DbErr};
-use tokio::sync::{watch, mpsc};
-use tracing::{info, error, warn};
-use std::collections::VecDeque;
+use sea_orm::{TransactionTrait, TransactionError, ConnectionTrait, DatabaseConnection, EntityTrait, Condition, ColumnTrait, QueryFilter, Set, QueryOrder, Order, ActiveModelTrait, ActiveValue::{NotSet, self}, DbErr, PaginatorTrait, sea_query::Expr};
+use tokio::sync::{watch, mpsc, oneshot};
+use tracing::{debug, info, error, warn};
+use std::{collections::{VecDeque, HashMap}, path::PathBuf, sync::Arc, time::Duration};
 
-use crate::data::{entities, FetchError};
+use crate::data::{self, entities, FetchError, Payload};
+use crate::util::timestamp_to_str;
+
+use super::{fetcher::Fetcher, chain, transfer::{self, Columns}};
 
 #[derive(Clone)]
 pub struct AppStateView {
@@ -13,6 +16,7 @@ pub struct AppStateView {
 	pub metrics:      watch::Receiver<Vec<entities::metrics::Model>>,
 	pub panel_metric: watch::Receiver<Vec<entities::panel_metric::Model>>,
 	pub points:       watch::Receiver<Vec<entities::points::Model>>,
+	pub payloads:     watch::Receiver<HashMap<i64, Payload>>,
 	pub flush:        mpsc::Sender<()>,
 	pub op:           mpsc::Sender<BackgroundAction>,
 }
@@ -32,6 +36,7 @@ struct AppStateTransmitters {
 	metrics:      watch::Sender<Vec<entities::metrics::Model>>,
 	points:       watch::Sender<Vec<entities::points::Model>>,
 	panel_metric: watch::Sender<Vec<entities::panel_metric::Model>>,
+	payloads:     watch::Sender<HashMap<i64, Payload>>,
 }
 
 pub struct AppState {
@@ -48,6 +53,9 @@ pub struct AppState {
 	points:  VecDeque<entities::points::Model>,
 	last_check: i64,
 
+	fetcher: Arc<Fetcher>,
+	read_only: bool,
+
 	flush: mpsc::Receiver<()>,
 	op: mpsc::Receiver<BackgroundAction>,
 
@@ -60,6 +68,115 @@ pub struct AppState {
 	view: AppStateView,
 }
 
+// how long to wait for a payload requested by the editor before giving up
+const PAYLOAD_TIMEOUT : Duration = Duration::from_secs(30);
+
+// how many archived payloads are decoded and turned into points at once
+const BACKFILL_PAGE : u64 = 200;
+
+/// Extract metric from archived payloads older than its first point, inserting points at the time
+/// payloads were fetched. Returns how many payloads were read and how many points inserted.
+async fn backfill(db: &DatabaseConnection, metric: &entities::metrics::Model) -> Result<(usize, usize), FetchError> {
+	let first = entities::points::Entity::find()
+		.filter(entities::points::Column::MetricId.eq(metric.id))
+		.order_by(entities::points::Column::X, Order::Asc)
+		.one(db).await?;
+	let mut condition = Condition::all().add(entities::payloads::Column::SourceId.eq(metric.source_id));
+	if let Some(p) = first {
+		condition = condition.add(entities::payloads::Column::FetchedAt.lt(p.x));
+	}
+	let mut pages = entities::payloads::Entity::find()
+		.filter(condition)
+		.order_by(entities::payloads::Column::FetchedAt, Order::Asc)
+		.paginate(db, BACKFILL_PAGE);
+
+	let (mut read, mut inserted) = (0, 0);
+	while let Some(page) = pages.fetch_and_next().await? {
+		read += page.len();
+		let mut points = vec![];
+		for archived in page {
+			match archived.unpack().and_then(|p| metric.extract(&p)) {
+				Ok(Some(raw)) => match metric.validate(raw) {
+					Some((y, flagged)) => points.push(entities::points::ActiveModel {
+						id: NotSet, metric_id: Set(metric.id), x: Set(archived.fetched_at), y: Set(y), flagged: Set(flagged),
+					}),
+					None => debug!(target: "backfill", "Rejected value {} for '{}' archived at {}", raw, metric.name, archived.fetched_at),
+				},
+				Ok(None) => {},
+				Err(e) => warn!(target: "backfill", "Could not extract '{}' from payload archived at {}: {:?}", metric.name, archived.fetched_at, e),
+			}
+		}
+		inserted += points.len();
+		if !points.is_empty() {
+			entities::points::Entity::insert_many(points).exec(db).await?;
+		}
+	}
+	Ok((read, inserted))
+}
+
+/// Points to plot between `from` and `to`: raw points plus rollups of old data, sorted by x
+/// Drop rollups overlapping data of a finer resolution, raw points being the finest, so each
+/// stretch of time is plotted once and as detailed as stored. Rollups must be sorted by resolution.
+fn finest_rollups(points: &[entities::points::Model], rollups: Vec<entities::rollups::Model>) -> Vec<entities::rollups::Model> {
+	fn extend(spans: &mut HashMap<i64, (f64, f64)>, metric_id: i64, start: f64, end: f64) {
+		spans.entry(metric_id)
+			.and_modify(|(s, e)| { *s = s.min(start); *e = e.max(end); })
+			.or_insert((start, end));
+	}
+	// time spanned by each metric at finer resolutions than the one being looked at
+	let mut covered : Vec<HashMap<i64, (f64, f64)>> = vec![HashMap::new()];
+	for p in points {
+		extend(&mut covered[0], p.metric_id, p.x, p.x);
+	}
+	let mut out = Vec::with_capacity(rollups.len());
+	let mut resolution = None;
+	for r in rollups {
+		if resolution != Some(r.resolution) {
+			resolution = Some(r.resolution);
+			covered.push(HashMap::new());
+		}
+		let (start, end) = (r.x, r.x + r.resolution as f64);
+		let overlaps = covered[..covered.len() - 1].iter()
+			.filter_map(|spans| spans.get(&r.metric_id))
+			.any(|(s, e)| start <= *e && end > *s);
+		if !overlaps {
+			let spans = covered.last_mut().expect("pushed above");
+			extend(spans, r.metric_id, start, end);
+			out.push(r);
+		}
+	}
+	out
+}
+
+async fn load_window(db: &DatabaseConnection, from: f64, to: f64) -> Result<Vec<entities::points::Model>, DbErr> {
+	let mut points = entities::points::Entity::find()
+		.filter(
+			Condition::all()
+				.add(entities::points::Column::Flagged.eq(false))
+				.add(entities::points::Column::X.gte(from))
+				.add(entities::points::Column::X.lte(to))
+		)
+		.order_by(entities::points::Column::X, Order::Asc)
+		.all(db)
+		.await?;
+	let rollups = entities::rollups::Entity::find()
+		.filter(
+			Condition::all()
+				.add(entities::rollups::Column::X.gte(from))
+				.add(entities::rollups::Column::X.lte(to))
+				.add(entities::rollups::Column::Resolution.lte(to - from))
+		)
+		.order_by(entities::rollups::Column::Resolution, Order::Asc)
+		.all(db)
+		.await?;
+	let rollups = finest_rollups(&points, rollups);
+	if !rollups.is_empty() {
+		points.extend(rollups.into_iter().map(entities::points::Model::from));
+		points.sort_by(|a, b| a.x.total_cmp(&b.x));
+	}
+	Ok(points)
+}
+
 async fn sleep(t:i64) {
 	if t > 0 {
 		tokio::time::sleep(std::time::Duration::from_secs(t as u64)).await
@@ -72,12 +189,14 @@ impl AppState {
 		db_uri: mpsc::Receiver<String>,
 		interval: i64,
 		cache_age: i64,
+		read_only: bool,
 	) -> Result<AppState, FetchError> {
 		let (panel_tx, panel_rx) = watch::channel(vec![]);
 		let (source_tx, source_rx) = watch::channel(vec![]);
 		let (metric_tx, metric_rx) = watch::channel(vec![]);
 		let (point_tx, point_rx) = watch::channel(vec![]);
 		let (panel_metric_tx, panel_metric_rx) = watch::channel(vec![]);
+		let (payload_tx, payload_rx) = watch::channel(HashMap::new());
 		// let (view_tx, view_rx) = watch::channel(0);
 		let (flush_tx, flush_rx) = mpsc::channel(10);
 		let (op_tx, op_rx) = mpsc::channel(100);
@@ -90,6 +209,8 @@ impl AppState {
 			last_refresh: 0,
 			points: VecDeque::new(),
 			last_check: 0,
+			fetcher: Arc::new(Fetcher::default()),
+			read_only,
 			last_width: 0,
 			flush: flush_rx,
 			op: op_rx,
@@ -99,6 +220,7 @@ impl AppState {
 				metrics: metric_rx,
 				points: point_rx,
 				panel_metric: panel_metric_rx,
+				payloads: payload_rx,
 				flush: flush_tx,
 				op: op_tx,
 			},
@@ -108,6 +230,7 @@ impl AppState {
 				metrics: metric_tx,
 				points: point_tx,
 				panel_metric: panel_metric_tx,
+				payloads: payload_tx,
 			},
 			width,
 			db_uri,
@@ -227,6 +350,12 @@ impl AppState {
 				self.view.request_flush().await;
 			},
 			BackgroundAction::UpdateMetric { metric } => {
+				if let (ActiveValue::Set(Some(min)), ActiveValue::Set(Some(max))) = (&metric.valid_min, &metric.valid_max) {
+					if min > max {
+						error!(target: "state-manager", "Refusing to save metric: valid min {} is above max {}", min, max);
+						return Ok(());
+					}
+				}
 				let op = if metric.id == NotSet { metric.insert(db) } else { metric.update(db) };
 				if let Err(e) = op.await {
 					error!(target: "state-manager", "Could not update metric: {:?}", e);
@@ -234,6 +363,99 @@ impl AppState {
 					self.view.request_flush().await;
 				}
 			},
+			BackgroundAction::CreateMetrics { metrics } => {
+				let count = metrics.len();
+				if count == 0 {
+					return Ok(());
+				}
+				if let Err(e) = entities::metrics::Entity::insert_many(metrics).exec(db).await {
+					error!(target: "state-manager", "Could not create metrics: {:?}", e);
+				} else {
+					info!(target: "state-manager", "Created {} metrics", count);
+					self.view.request_flush().await;
+				}
+			},
+			BackgroundAction::Backfill { metric } => {
+				// may take a while on big archives, don't block other operations
+				let db = db.clone();
+				let view = self.view.clone();
+				tokio::spawn(async move {
+					info!(target: "backfill", "Backfilling metric '{}' from archived payloads", metric.name);
+					match backfill(&db, &metric).await {
+						Ok((read, inserted)) => {
+							info!(target: "backfill", "Inserted {} points for metric '{}' from {} archived payloads", inserted, metric.name, read);
+							view.request_flush().await;
+						},
+						Err(e) => error!(target: "backfill", "Could not backfill metric '{}': {:?}", metric.name, e),
+					}
+				});
+			},
+			BackgroundAction::FetchPayload { source } => {
+				// slow sources must not hold back other operations
+				let fetcher = self.fetcher.clone();
+				let sources = self.sources.clone();
+				let payloads = self.tx.payloads.clone();
+				tokio::spawn(async move {
+					let fetch = async {
+						let resolved = chain::resolve(&fetcher, &source, &sources).await?;
+						fetcher.fetch(&resolved).await
+					};
+					match tokio::time::timeout(PAYLOAD_TIMEOUT, fetch).await {
+						Ok(Ok(payload)) => payloads.send_modify(|p| { p.insert(source.id, payload); }),
+						Ok(Err(e)) => error!(target: "state-manager", "Could not fetch payload of source '{}': {:?}", source.name, e),
+						Err(_) => error!(target: "state-manager", "Timed out fetching payload of source '{}' after {}s", source.name, PAYLOAD_TIMEOUT.as_secs()),
+					}
+				});
+			},
+			BackgroundAction::CountPoints { target, reply } => {
+				// always answer, popup would otherwise wait forever
+				let count = count_points(db, target).await;
+				if reply.send(count.as_ref().copied().map_err(|e| e.to_string())).is_err() {
+					warn!(target: "state-manager", "Nobody waiting for points count of {:?}", target);
+				}
+				count?;
+			},
+			BackgroundAction::Delete { target, points } => {
+				delete(db, target, points).await?;
+				info!(target: "state-manager", "Deleted {:?} (points: {})", target, points);
+				self.view.request_flush().await;
+			},
+			BackgroundAction::CountRange { metrics, from, to, reply } => {
+				let count = count_range(db, &metrics, from, to).await;
+				if reply.send(count.as_ref().copied().map_err(|e| e.to_string())).is_err() {
+					warn!(target: "state-manager", "Nobody waiting for points count of metrics {:?}", metrics);
+				}
+				count?;
+			},
+			BackgroundAction::EditRange { metrics, from, to, edit } => {
+				let count = edit_range(db, &metrics, from, to, edit).await?;
+				info!(
+					target: "state-manager", "{} {} points and {} rollups of metrics {:?} between {} and {}",
+					edit.label(), count.points, count.rollups, metrics, timestamp_to_str(from as i64, true, true), timestamp_to_str(to as i64, true, true),
+				);
+				self.view.request_flush().await;
+			},
+			BackgroundAction::ExportCsv { metric, path } => {
+				match transfer::export(db, &metric, None, None, path.clone()).await {
+					Ok(count) => info!(target: "transfer", "Exported {} points of '{}' to {}", count, metric.name, path.display()),
+					Err(e) => error!(target: "transfer", "Could not export '{}' to {}: {}", metric.name, path.display(), e),
+				}
+			},
+			BackgroundAction::ImportCsv { metric, path } => {
+				match transfer::import(db, &metric, path.clone(), Columns::default(), false).await {
+					Ok(report) => {
+						info!(
+							target: "transfer", "Imported {} points into '{}' from {} ({} flagged, {} already stored, {} rejected)",
+							report.inserted, metric.name, path.display(), report.flagged, report.skipped, report.rejected,
+						);
+						for e in report.malformed.iter() {
+							warn!(target: "transfer", "Skipped malformed {}", e);
+						}
+						self.view.request_flush().await;
+					},
+					Err(e) => error!(target: "transfer", "Could not import {} into '{}': {}", path.display(), metric.name, e),
+				}
+			},
 			// _ => todo!(),
 		}
 		Ok(())
@@ -243,15 +465,7 @@ impl AppState {
 		let now = Utc::now().timestamp();
 		self.fetch(db).await?;
 		self.last_width = *self.width.borrow() * 60; // TODO it's in minutes somewhere...
-		self.points = entities::points::Entity::find()
-			.filter(
-				Condition::all()
-					.add(entities::points::Column::X.gte((now - self.last_width) as f64))
-					.add(entities::points::Column::X.lte(now as f64))
-			)
-			.order_by(entities::points::Column::X, Order::Asc)
-			.all(db)
-			.await?.into();
+		self.points = load_window(db, (now - self.last_width) as f64, now as f64).await?.into();
 		if let Err(e) = self.tx.points.send(self.points.clone().into()) {
 			warn!(target: "state-manager", "Could not send new points: {:?}", e); // TODO should be an err?
 		}
@@ -266,16 +480,8 @@ impl AppState {
 	
 		// fetch previous points
 		if new_width != self.last_width {
-			let previous_points = entities::points::Entity::find()
-				.filter(
-					Condition::all()
-						.add(entities::points::Column::X.gte(now - new_width))
-						.add(entities::points::Column::X.lte(now - self.last_width))
-				)
-				.order_by(entities::points::Column::X, Order::Desc)
-				.all(db)
-				.await?;
-			for p in previous_points {
+			let previous_points = load_window(db, (now - new_width) as f64, (now - self.last_width) as f64).await?;
+			for p in previous_points.into_iter().rev() {
 				self.points.push_front(p);
 				changes = true;
 			}
@@ -286,6 +492,7 @@ impl AppState {
 		let new_points = entities::points::Entity::find()
 			.filter(
 				Condition::all()
+					.add(entities::points::Column::Flagged.eq(false))
 					.add(entities::points::Column::X.gte(lower_bound as f64))
 					.add(entities::points::Column::X.lte(now as f64))
 			)
@@ -325,7 +532,13 @@ impl AppState {
 			return;
 		};
 
-		let mut db = Database::connect(first_db_uri.clone()).await.unwrap();
+		let mut db = match data::connect(&first_db_uri, self.read_only).await {
+			Ok(db) => db,
+			Err(e) => {
+				error!(target: "state-manager", "Could not connect to '{}': {:?}", first_db_uri, e);
+				return;
+			},
+		};
 
 		info!(target: "state-manager", "Connected to '{}'", first_db_uri);
 
@@ -335,7 +548,7 @@ impl AppState {
 				res = self.db_uri.recv() => {
 					match res {
 						Some(uri) => {
-							match Database::connect(uri.clone()).await {
+							match data::connect(&uri, self.read_only).await {
 								Ok(new_db) => {
 									info!("Connected to '{}'", uri);
 									db = new_db;
@@ -381,13 +594,326 @@ impl AppState {
 	}
 }
 
+/// Something to delete, together with what depends on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteTarget {
+	Panel(i64),
+	Source(i64),
+	Metric(i64),
+}
+
+impl DeleteTarget {
+	pub fn label(&self) -> &'static str {
+		match self {
+			DeleteTarget::Panel(_) => "panel",
+			DeleteTarget::Source(_) => "source",
+			DeleteTarget::Metric(_) => "metric",
+		}
+	}
+
+	/// Metrics whose points would be destroyed with target
+	async fn metric_ids<C: ConnectionTrait>(&self, db: &C) -> Result<Vec<i64>, DbErr> {
+		match self {
+			DeleteTarget::Panel(_) => Ok(vec![]),
+			DeleteTarget::Metric(id) => Ok(vec![*id]),
+			DeleteTarget::Source(id) => Ok(
+				entities::metrics::Entity::find()
+					.filter(entities::metrics::Column::SourceId.eq(*id))
+					.all(db).await?
+					.into_iter()
+					.map(|m| m.id)
+					.collect()
+			),
+		}
+	}
+}
+
+/// How many points (raw or rolled up) deleting target with its points would destroy
+async fn count_points(db: &DatabaseConnection, target: DeleteTarget) -> Result<u64, DbErr> {
+	let metric_ids = target.metric_ids(db).await?;
+	if metric_ids.is_empty() {
+		return Ok(0);
+	}
+	Ok(
+		entities::points::Entity::find()
+			.filter(entities::points::Column::MetricId.is_in(metric_ids.clone()))
+			.count(db).await?
+		+ entities::rollups::Entity::find()
+			.filter(entities::rollups::Column::MetricId.is_in(metric_ids))
+			.count(db).await?
+	)
+}
+
+/// Delete target and rows referencing it: panel/metric links, metrics of sources and archived
+/// payloads. Points are only deleted if `points` is set, otherwise they're left orphaned.
+async fn delete(db: &DatabaseConnection, target: DeleteTarget, points: bool) -> Result<(), DbErr> {
+	db.transaction::<_, (), DbErr>(|txn| {
+		Box::pin(async move {
+			let metric_ids = target.metric_ids(txn).await?;
+			if points && !metric_ids.is_empty() {
+				entities::points::Entity::delete_many()
+					.filter(entities::points::Column::MetricId.is_in(metric_ids.clone()))
+					.exec(txn).await?;
+				entities::rollups::Entity::delete_many()
+					.filter(entities::rollups::Column::MetricId.is_in(metric_ids.clone()))
+					.exec(txn).await?;
+			}
+			if !metric_ids.is_empty() {
+				entities::panel_metric::Entity::delete_many()
+					.filter(entities::panel_metric::Column::MetricId.is_in(metric_ids.clone()))
+					.exec(txn).await?;
+				entities::metrics::Entity::delete_many()
+					.filter(entities::metrics::Column::Id.is_in(metric_ids))
+					.exec(txn).await?;
+			}
+			match target {
+				DeleteTarget::Panel(id) => {
+					entities::panel_metric::Entity::delete_many()
+						.filter(entities::panel_metric::Column::PanelId.eq(id))
+						.exec(txn).await?;
+					entities::panels::Entity::delete_by_id(id).exec(txn).await?;
+				},
+				DeleteTarget::Source(id) => {
+					entities::payloads::Entity::delete_many()
+						.filter(entities::payloads::Column::SourceId.eq(id))
+						.exec(txn).await?;
+					entities::sources::Entity::delete_by_id(id).exec(txn).await?;
+				},
+				DeleteTarget::Metric(_) => {}, // already removed with its links
+			}
+			Ok(())
+		})
+	}).await.map_err(|e| match e {
+		TransactionError::Connection(e) => e,
+		TransactionError::Transaction(e) => e,
+	})
+}
+
+/// What to do with points picked on a plot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeEdit {
+	/// remove them for good
+	Delete,
+	/// keep them but hide them from plots and rollups, like values flagged at ingest
+	Flag,
+}
+
+impl RangeEdit {
+	pub fn label(&self) -> &'static str {
+		match self {
+			RangeEdit::Delete => "Deleted",
+			RangeEdit::Flag => "Flagged",
+		}
+	}
+}
+
+/// Rows of some metrics in an x range: raw points and rolled up aggregates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeCount {
+	pub points: u64,
+	pub rollups: u64,
+}
+
+impl RangeCount {
+	/// Rolled up samples are gone, so rollups can't be flagged nor recomputed without bad points
+	pub fn flaggable(&self) -> bool {
+		self.points > 0 && self.rollups == 0
+	}
+}
+
+/// How many points (raw or rolled up) of metrics fall between from and to. Flagged points are
+/// counted too since deleting would remove them.
+async fn count_range<C: ConnectionTrait>(db: &C, metrics: &[i64], from: f64, to: f64) -> Result<RangeCount, DbErr> {
+	Ok(RangeCount {
+		points: entities::points::Entity::find()
+			.filter(entities::points::Column::MetricId.is_in(metrics.to_vec()))
+			.filter(entities::points::Column::X.between(from, to))
+			.count(db).await?,
+		rollups: entities::rollups::Entity::find()
+			.filter(entities::rollups::Column::MetricId.is_in(metrics.to_vec()))
+			.filter(entities::rollups::Column::X.between(from, to))
+			.count(db).await?,
+	})
+}
+
+/// Delete or flag points of metrics between from and to, returns how many rows were changed.
+/// Deleting removes rollups in range too, flagging is refused if range has any.
+async fn edit_range(db: &DatabaseConnection, metrics: &[i64], from: f64, to: f64, edit: RangeEdit) -> Result<RangeCount, DbErr> {
+	let metrics = metrics.to_vec();
+	db.transaction::<_, RangeCount, DbErr>(|txn| {
+		Box::pin(async move {
+			match edit {
+				RangeEdit::Delete => Ok(RangeCount {
+					points: entities::points::Entity::delete_many()
+						.filter(entities::points::Column::MetricId.is_in(metrics.clone()))
+						.filter(entities::points::Column::X.between(from, to))
+						.exec(txn).await?
+						.rows_affected,
+					rollups: entities::rollups::Entity::delete_many()
+						.filter(entities::rollups::Column::MetricId.is_in(metrics))
+						.filter(entities::rollups::Column::X.between(from, to))
+						.exec(txn).await?
+						.rows_affected,
+				}),
+				RangeEdit::Flag => {
+					let rollups = count_range(txn, &metrics, from, to).await?.rollups;
+					if rollups > 0 {
+						return Err(DbErr::Custom(format!("range holds {} rollups, which can't be flagged", rollups)));
+					}
+					Ok(RangeCount {
+						points: entities::points::Entity::update_many()
+							.col_expr(entities::points::Column::Flagged, Expr::value(true))
+							.filter(entities::points::Column::MetricId.is_in(metrics))
+							.filter(entities::points::Column::X.between(from, to))
+							.exec(txn).await?
+							.rows_affected,
+						rollups: 0,
+					})
+				},
+			}
+		})
+	}).await.map_err(|e| match e {
+		TransactionError::Connection(e) => e,
+		TransactionError::Transaction(e) => e,
+	})
+}
+
 #[derive(Debug)]
 pub enum BackgroundAction {
 	UpdateAllPanels { panels: Vec<entities::panels::Model> },
 	UpdatePanel     { panel : entities::panels::ActiveModel, metrics: Vec<entities::panel_metric::ActiveModel> },
 	UpdateSource    { source: entities::sources::ActiveModel },
 	UpdateMetric    { metric: entities::metrics::ActiveModel },
+	FetchPayload    { source: entities::sources::Model },
+	CreateMetrics   { metrics: Vec<entities::metrics::ActiveModel> },
+	Backfill        { metric: entities::metrics::Model },
+	CountPoints     { target: DeleteTarget, reply: oneshot::Sender<Result<u64, String>> },
+	Delete          { target: DeleteTarget, points: bool },
+	CountRange      { metrics: Vec<i64>, from: f64, to: f64, reply: oneshot::Sender<Result<RangeCount, String>> },
+	EditRange       { metrics: Vec<i64>, from: f64, to: f64, edit: RangeEdit },
+	ExportCsv       { metric: entities::metrics::Model, path: PathBuf },
+	ImportCsv       { metric: entities::metrics::Model, path: PathBuf },
 	// InsertPanel     { panel : entities::panels::ActiveModel },
 	// InsertSource    { source: entities::sources::ActiveModel },
 	// InsertMetric    { metric: entities::metrics::ActiveModel },
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::data::{test_db, test_metric};
+
+	async fn archive(db: &DatabaseConnection, at: f64, payload: serde_json::Value) {
+		let model = entities::payloads::Model::pack(1, at, &Payload::Json(payload)).unwrap();
+		entities::payloads::Entity::insert(model).exec(db).await.unwrap();
+	}
+
+	async fn point(db: &DatabaseConnection, x: f64, y: f64) {
+		entities::points::Entity::insert(entities::points::ActiveModel {
+			id: NotSet, metric_id: Set(1), x: Set(x), y: Set(y), flagged: Set(false),
+		}).exec(db).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn backfill_only_fills_before_first_point() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |m| m.query = "\"v\"".into()).await;
+		for (at, v) in [(100.0, 1.0), (200.0, 2.0), (300.0, 3.0)] {
+			archive(&db, at, serde_json::json!({ "v": v })).await;
+		}
+		archive(&db, 150.0, serde_json::json!({ "other": 0 })).await;
+		point(&db, 250.0, 2.5).await;
+
+		assert_eq!(backfill(&db, &metric).await.unwrap(), (3, 2));
+		let xs : Vec<f64> = entities::points::Entity::find()
+			.order_by_asc(entities::points::Column::X)
+			.all(&db).await.unwrap()
+			.iter().map(|p| p.x).collect();
+		assert_eq!(xs, vec![100.0, 200.0, 250.0]);
+
+		// nothing older than first point is left
+		assert_eq!(backfill(&db, &metric).await.unwrap(), (0, 0));
+	}
+
+	#[tokio::test]
+	async fn backfill_validates_values() {
+		let db = test_db().await;
+		let metric = test_metric(&db, |m| {
+			m.query = "\"v\"".into();
+			m.valid_max = Some(10.0);
+			m.out_of_range = entities::metrics::OutOfRange::Flag;
+		}).await;
+		archive(&db, 100.0, serde_json::json!({ "v": 5 })).await;
+		archive(&db, 200.0, serde_json::json!({ "v": 50 })).await;
+
+		assert_eq!(backfill(&db, &metric).await.unwrap(), (2, 2));
+		let flagged : Vec<bool> = entities::points::Entity::find()
+			.order_by_asc(entities::points::Column::X)
+			.all(&db).await.unwrap()
+			.iter().map(|p| p.flagged).collect();
+		assert_eq!(flagged, vec![false, true]);
+	}
+
+	fn rollup(metric_id: i64, resolution: i32, x: f64) -> entities::rollups::Model {
+		entities::rollups::Model { id: x as i64 + resolution as i64, metric_id, resolution, x, min: 0.0, max: 0.0, avg: 0.0, count: 1 }
+	}
+
+	#[test]
+	fn finer_data_hides_overlapping_rollups() {
+		let points = vec![
+			entities::points::Model { id: 1, metric_id: 1, x: 7200.0, y: 1.0, flagged: false },
+			entities::points::Model { id: 2, metric_id: 1, x: 7300.0, y: 1.0, flagged: false },
+		];
+		let rollups = vec![
+			rollup(1, 60, 7140.0),
+			rollup(1, 60, 7200.0), // raw points already there
+			rollup(2, 60, 7200.0), // other metric
+			rollup(1, 3600, 0.0),
+			rollup(1, 3600, 3600.0), // minute rollups already there
+			rollup(1, 86400, 0.0),
+		];
+		let kept : Vec<_> = finest_rollups(&points, rollups).iter().map(|r| (r.metric_id, r.resolution, r.x)).collect();
+		assert_eq!(kept, vec![(1, 60, 7140.0), (2, 60, 7200.0), (1, 3600, 0.0)]);
+	}
+
+	async fn stored_rollup(db: &DatabaseConnection, x: f64) {
+		entities::rollups::Entity::insert(entities::rollups::ActiveModel {
+			id: NotSet, metric_id: Set(1), resolution: Set(60), x: Set(x), min: Set(1.0), max: Set(1.0), avg: Set(1.0), count: Set(3),
+		}).exec(db).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn flagging_keeps_points_and_refuses_rollups() {
+		let db = test_db().await;
+		test_metric(&db, |_| {}).await;
+		stored_rollup(&db, 0.0).await;
+		for x in [100.0, 110.0, 200.0] {
+			point(&db, x, 1.0).await;
+		}
+		assert_eq!(count_range(&db, &[1], 0.0, 150.0).await.unwrap(), RangeCount { points: 2, rollups: 1 });
+		assert!(edit_range(&db, &[1], 0.0, 150.0, RangeEdit::Flag).await.is_err());
+		assert_eq!(entities::rollups::Entity::find().count(&db).await.unwrap(), 1);
+
+		let flagged = edit_range(&db, &[1], 90.0, 150.0, RangeEdit::Flag).await.unwrap();
+		assert_eq!(flagged, RangeCount { points: 2, rollups: 0 });
+		let points = entities::points::Entity::find().order_by_asc(entities::points::Column::X).all(&db).await.unwrap();
+		assert_eq!(points.iter().map(|p| p.flagged).collect::<Vec<_>>(), vec![true, true, false]);
+		assert_eq!(entities::rollups::Entity::find().count(&db).await.unwrap(), 1);
+	}
+
+	#[tokio::test]
+	async fn deleting_removes_points_and_rollups() {
+		let db = test_db().await;
+		test_metric(&db, |_| {}).await;
+		stored_rollup(&db, 0.0).await;
+		stored_rollup(&db, 300.0).await;
+		point(&db, 100.0, 1.0).await;
+		point(&db, 400.0, 1.0).await;
+		let deleted = edit_range(&db, &[1], 0.0, 150.0, RangeEdit::Delete).await.unwrap();
+		assert_eq!(deleted, RangeCount { points: 1, rollups: 1 });
+		assert_eq!(count_range(&db, &[1], 0.0, 1000.0).await.unwrap(), RangeCount { points: 1, rollups: 1 });
+		// other metrics are left alone
+		assert_eq!(edit_range(&db, &[2], 0.0, 1000.0, RangeEdit::Delete).await.unwrap(), RangeCount::default());
+	}
+}
+